@@ -0,0 +1,119 @@
+//! Replays arbitrary sequences of alloc, dealloc and realloc against one `Allocator<AVLTree>`.
+//!
+//! Every allocation is filled with its own byte and checked in full before it's freed or moved,
+//! so handing out overlapping memory shows up as a mismatch. Alignment is checked on every
+//! pointer returned, and the `validate` feature has the trees check themselves after every
+//! change, as fuzzing builds keep debug assertions on.
+
+#![no_main]
+
+use std::alloc::{GlobalAlloc, Layout};
+
+use alloc_expr::{AVLTree, Allocator};
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+/// Large enough to go past a few pages, small enough that checking every byte stays quick.
+const MAX_LARGE_SIZE: usize = 1 << 20;
+
+/// Largest alignment asked for, past a page so the large allocator has to place data inside a
+/// chunk.
+const MAX_ALIGN_SHIFT: u8 = 21;
+
+static ALLOCATOR: Allocator<AVLTree> = Allocator::new();
+
+#[derive(Arbitrary, Debug)]
+enum Size {
+    Small(u16),
+    Large(u32),
+}
+
+impl Size {
+    fn bytes(&self) -> usize {
+        match *self {
+            Size::Small(size) => usize::from(size) % 4096 + 1,
+            Size::Large(size) => size as usize % MAX_LARGE_SIZE + 1,
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Alloc { size: Size, align_shift: u8 },
+    Dealloc { index: u16 },
+    Realloc { index: u16, size: Size },
+}
+
+struct Allocation {
+    ptr: *mut u8,
+    layout: Layout,
+    pattern: u8,
+}
+
+impl Allocation {
+    unsafe fn check(&self, len: usize) {
+        let bytes = std::slice::from_raw_parts(self.ptr, len);
+        if let Some(offset) = bytes.iter().position(|&byte| byte != self.pattern) {
+            panic!("byte {offset} of {:?} at {:p} was overwritten", self.layout, self.ptr);
+        }
+    }
+}
+
+unsafe fn checked(ptr: *mut u8, layout: Layout) -> *mut u8 {
+    assert!(!ptr.is_null(), "allocating {layout:?} failed");
+    assert!((ptr as usize).is_multiple_of(layout.align()), "{ptr:p} isn't aligned for {layout:?}");
+    ptr
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut live: Vec<Allocation> = Vec::new();
+    let mut next_pattern: u8 = 0;
+
+    for op in ops {
+        unsafe {
+            match op {
+                Op::Alloc { size, align_shift } => {
+                    let align = 1 << (align_shift % (MAX_ALIGN_SHIFT + 1));
+                    let layout = Layout::from_size_align(size.bytes(), align).unwrap();
+                    let ptr = checked(ALLOCATOR.alloc(layout), layout);
+
+                    next_pattern = next_pattern.wrapping_add(1);
+                    ptr.write_bytes(next_pattern, layout.size());
+                    live.push(Allocation { ptr, layout, pattern: next_pattern });
+                }
+                Op::Dealloc { index } if !live.is_empty() => {
+                    let allocation = live.swap_remove(usize::from(index) % live.len());
+                    allocation.check(allocation.layout.size());
+                    ALLOCATOR.dealloc(allocation.ptr, allocation.layout);
+                }
+                Op::Realloc { index, size } if !live.is_empty() => {
+                    let index = usize::from(index) % live.len();
+                    let allocation = &mut live[index];
+                    allocation.check(allocation.layout.size());
+
+                    let new_layout = Layout::from_size_align(size.bytes(), allocation.layout.align()).unwrap();
+                    let ptr = ALLOCATOR.realloc(allocation.ptr, allocation.layout, new_layout.size());
+                    let kept = allocation.layout.size().min(new_layout.size());
+                    allocation.ptr = checked(ptr, new_layout);
+                    allocation.layout = new_layout;
+                    allocation.check(kept);
+                    ptr.write_bytes(allocation.pattern, new_layout.size());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    for allocation in live {
+        unsafe {
+            allocation.check(allocation.layout.size());
+            ALLOCATOR.dealloc(allocation.ptr, allocation.layout);
+        }
+    }
+
+    // with everything freed the counts have to come back to nothing handed out
+    let stats = ALLOCATOR.stats();
+    assert_eq!(stats.large.allocated_bytes, 0, "{stats:?}");
+    assert!(stats.classes.iter().all(|class| class.allocated == 0), "{stats:?}");
+    ALLOCATOR.trim();
+});
@@ -0,0 +1,10 @@
+//! `malloc`, `free` and the rest of the family backed by alloc_expr, built into
+//! `liballoc_expr.so`. From this directory:
+//!
+//! ```sh
+//! cargo build --release
+//! LD_PRELOAD=target/release/liballoc_expr.so ls
+//! ```
+
+// nothing here is used directly, linking the crate in is what exports its symbols
+extern crate alloc_expr_lib;
@@ -0,0 +1,182 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{copy_nonoverlapping, NonNull};
+
+use allocator_api2::alloc::{AllocError, Allocator as AllocatorApi};
+
+use crate::lock::Mutex;
+use crate::{Allocator, LargeAllocator};
+
+// allocator-api2's `Allocator`, which is core's `Allocator` on nightly, lets a single `Vec` or
+// `Box` use an allocator of its own. Zero sized requests never reach the allocators, they get a
+// dangling pointer as the trait allows. Every block comes back with its real capacity, the whole
+// object for a size class and up to the end of the chunk for a large allocation.
+
+/// A large allocator behind a lock, so collections can share it through `&Locked<T>`.
+pub struct Locked<T: LargeAllocator> {
+    inner: Mutex<T>,
+}
+
+impl<T: LargeAllocator> Locked<T> {
+    pub const fn new(inner: T) -> Self {
+        Locked { inner: Mutex::new(inner) }
+    }
+
+    /// Runs `f` on the allocator with the lock held, to read its stats for instance.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.inner.lock())
+    }
+}
+
+/// What the trait needs from each allocator, with non-zero sizes only.
+trait Backend {
+    unsafe fn alloc(&self, layout: Layout) -> Option<NonNull<[u8]>>;
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout);
+    unsafe fn realloc(&self, ptr: NonNull<u8>, layout: Layout, new_size: usize) -> Option<NonNull<[u8]>>;
+}
+
+impl<T: LargeAllocator + Default, const N: usize> Backend for Allocator<T, N> {
+    unsafe fn alloc(&self, layout: Layout) -> Option<NonNull<[u8]>> {
+        let ptr = NonNull::new(GlobalAlloc::alloc(self, layout))?;
+        Some(NonNull::slice_from_raw_parts(ptr, self.usable_size(ptr, layout)))
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        GlobalAlloc::dealloc(self, ptr.as_ptr(), layout);
+    }
+
+    unsafe fn realloc(&self, ptr: NonNull<u8>, layout: Layout, new_size: usize) -> Option<NonNull<[u8]>> {
+        let ptr = NonNull::new(GlobalAlloc::realloc(self, ptr.as_ptr(), layout, new_size))?;
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        Some(NonNull::slice_from_raw_parts(ptr, self.usable_size(ptr, new_layout)))
+    }
+}
+
+impl<T: LargeAllocator + Default, const N: usize> Allocator<T, N> {
+    /// Bytes that can be used at `ptr`, which was allocated with `layout`.
+    unsafe fn usable_size(&self, ptr: NonNull<u8>, layout: Layout) -> usize {
+        match self.classes.class_of(layout) {
+            Some(class) => self.classes.size(class),
+            None => self.with_large(|large| large.usable_size(ptr.as_ptr(), layout)),
+        }
+    }
+}
+
+impl<T: LargeAllocator> Backend for Locked<T> {
+    unsafe fn alloc(&self, layout: Layout) -> Option<NonNull<[u8]>> {
+        let mut inner = self.inner.lock();
+        let ptr = NonNull::new(inner.alloc(layout))?;
+        Some(NonNull::slice_from_raw_parts(ptr, inner.usable_size(ptr.as_ptr(), layout)))
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, _layout: Layout) {
+        self.inner.lock().dealloc(ptr.as_ptr());
+    }
+
+    unsafe fn realloc(&self, ptr: NonNull<u8>, layout: Layout, new_size: usize) -> Option<NonNull<[u8]>> {
+        let mut inner = self.inner.lock();
+        let ptr = NonNull::new(inner.realloc(ptr.as_ptr(), layout, new_size))?;
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        Some(NonNull::slice_from_raw_parts(ptr, inner.usable_size(ptr.as_ptr(), new_layout)))
+    }
+}
+
+fn dangling(layout: Layout) -> NonNull<[u8]> {
+    // the alignment is a non-zero power of two, so it makes a well aligned address
+    let ptr = unsafe { NonNull::new_unchecked(layout.align() as *mut u8) };
+    NonNull::slice_from_raw_parts(ptr, 0)
+}
+
+fn allocate(backend: &impl Backend, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+    if layout.size() == 0 {
+        return Ok(dangling(layout));
+    }
+    unsafe { backend.alloc(layout) }.ok_or(AllocError)
+}
+
+unsafe fn deallocate(backend: &impl Backend, ptr: NonNull<u8>, layout: Layout) {
+    if layout.size() != 0 {
+        backend.dealloc(ptr, layout);
+    }
+}
+
+/// Both `grow` and `shrink`. The backends' realloc keeps the alignment, so only a change of
+/// alignment has to go through a fresh block.
+unsafe fn reallocate(
+    backend: &impl Backend,
+    ptr: NonNull<u8>,
+    old_layout: Layout,
+    new_layout: Layout,
+) -> Result<NonNull<[u8]>, AllocError> {
+    if old_layout.size() == 0 {
+        return allocate(backend, new_layout);
+    }
+    if new_layout.size() == 0 {
+        backend.dealloc(ptr, old_layout);
+        return Ok(dangling(new_layout));
+    }
+
+    if new_layout.align() == old_layout.align() {
+        return backend.realloc(ptr, old_layout, new_layout.size()).ok_or(AllocError);
+    }
+
+    let new_ptr = backend.alloc(new_layout).ok_or(AllocError)?;
+    copy_nonoverlapping(ptr.as_ptr(), new_ptr.cast().as_ptr(), old_layout.size().min(new_layout.size()));
+    backend.dealloc(ptr, old_layout);
+    Ok(new_ptr)
+}
+
+unsafe impl<T: LargeAllocator + Default, const N: usize> AllocatorApi for Allocator<T, N> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        allocate(self, layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        deallocate(self, ptr, layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        reallocate(self, ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        reallocate(self, ptr, old_layout, new_layout)
+    }
+}
+
+unsafe impl<T: LargeAllocator> AllocatorApi for Locked<T> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        allocate(self, layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        deallocate(self, ptr, layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        reallocate(self, ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        reallocate(self, ptr, old_layout, new_layout)
+    }
+}
@@ -1,40 +1,44 @@
-use std::alloc::{alloc, Layout};
-use std::cmp::{max, Ordering};
-use std::mem::{size_of, swap};
-use std::ptr::NonNull;
-use crate::avl_tree::DeleteAction::{NoAction, SearchDelete};
-use crate::avl_tree::SearchDirection::{Left, Right, Root};
-use crate::common::{request_memory, PAGE_SIZE};
+use core::alloc::Layout;
+use core::cmp::{max, Ordering};
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ptr::NonNull;
+use crate::chunk;
+use crate::common::{release_memory, resize_memory, PAGE_SIZE};
 use crate::large_allocator::LargeAllocator;
+use crate::poison;
+use crate::stats::{MapCounters, Stats};
 
-/// When deleting in a binary search tree, to prevent keeping a parent pointer this
-/// enum enables the delete function the ability to know exactly what action to take upon
-/// finding the correct node to remove; this is important as it limits the amount of recrusive
-/// searches and also guarantees a correct outcome in the case of searching for a lower bound, as
-/// there might be multiple "lowerbounds"
-#[derive(Debug)]
-enum DeleteAction {
-    /// deletion was taken care of
-    NoAction,
-    /// search to delete again, as a root node has been swapped with its inorder successor
-    SearchDelete
-}
+type NodePtr = Option<NonNull<Node>>;
 
-#[derive(Clone, Copy)]
-enum SearchDirection {
-    Left(NonNull<Node>),
-    Right(NonNull<Node>),
-    Root,
-}
+/// Free bytes the tree holds on to by default before giving chunks back to the OS.
+pub const DEFAULT_MAX_CACHED_BYTES: usize = 64 * 1024 * 1024;
 
-type NodePtr = Option<NonNull<Node>>;
+/// An AVL tree of height h holds at least fib(h + 2) - 1 nodes, and there can't be more chunks
+/// than pages in a 64 bit address space, so no tree is ever taller than this.
+const MAX_HEIGHT: usize = 96;
+
+/// Whether every large allocation gets a guarded chunk of its own, see the `guard-pages` feature.
+const GUARD_PAGES: bool = cfg!(all(debug_assertions, feature = "guard-pages"));
 
 #[derive(Debug)]
 struct AvlHeader {
+    /// length of the whole chunk, header included
     size: usize,
     height: i32,
     left: NodePtr,
     right: NodePtr,
+    /// other free chunks of the same size. Only the node in the tree uses its links, the chunks
+    /// in its bucket hang off it through this and are never in the tree themselves
+    next: NodePtr,
+    /// the chunk in front of this one in the bucket, None for the node in the tree
+    prev: NodePtr,
+    /// length of the chunk right before this one in memory, zero if this chunk starts its mapping
+    prev_size: usize,
+    /// whether the chunk is in the tree rather than handed out
+    free: bool,
+    /// whether the chunk runs to the end of its mapping, otherwise another chunk follows it
+    last: bool,
 }
 
 #[derive(Debug)]
@@ -43,43 +47,182 @@ struct Node {
     data: *mut u8,
 }
 
+/// Best fit large allocator keeping free chunks in an AVL tree ordered by size.
+///
+/// Freed chunks are cached in the tree for reuse until they add up to more than
+/// `max_cached_bytes`, past that the largest ones are unmapped until it's back under the cap.
+///
+/// A chunk handed out from a larger free one is split at the next page boundary past its data.
+/// Each header records its neighbours in the same mapping, so when a chunk is freed it's merged
+/// with any free neighbours first and the pieces find their way back together.
+///
+/// With the `guard-pages` feature on in a debug build none of that happens. Every allocation is
+/// mapped on its own between two inaccessible pages, with its data ending right at the second
+/// one, and unmapped as soon as it's freed. Running off either end of the data, or touching it
+/// after it's freed, faults on the spot rather than corrupting another chunk's header.
+///
+/// With the `poison` feature on in a debug build, everything in a free chunk past its header is
+/// filled with a pattern, and whatever part of a chunk is about to be handed out again is checked
+/// for it first.
 pub struct AVLTree {
     root: NodePtr,
+    /// total size of every chunk in the tree
+    cached_bytes: usize,
+    max_cached_bytes: usize,
+    counters: MapCounters,
 }
 
-impl Node {
-
-    unsafe fn new(layout: Layout) -> NonNull<Node> {
-        let header_layout = Layout::new::<AvlHeader>();
-        let (total_layout, offset) = header_layout.extend(layout).unwrap();
+// the tree owns every chunk linked into it, nothing else points at them while they're free
+unsafe impl Send for AVLTree {}
 
-        let page_aligned_layout = total_layout.pad_to_align().align_to(PAGE_SIZE).unwrap();
+impl Node {
+    /// Length of a chunk that can hold `layout` wherever the chunk starts, None on overflow.
+    fn chunk_size(layout: Layout) -> Option<usize> {
+        chunk::chunk_size(size_of::<Node>(), layout)
+    }
 
-        // this can fail, but realistically we have 256 tib and I'm not writing a program that's
-        // getting near that any time soon with this malloc :)
-        let address = request_memory(page_aligned_layout.size());
+    /// Maps a new chunk big enough for `layout`, None if the size overflows or the OS refuses.
+    unsafe fn new(layout: Layout, counters: &mut MapCounters) -> Option<NonNull<Node>> {
+        let (address, size) = if GUARD_PAGES {
+            chunk::map_guarded(size_of::<Node>(), layout, counters)?
+        } else {
+            chunk::map(size_of::<Node>(), layout, counters)?
+        };
+        Some(Self::init(address, size))
+    }
 
+    /// Writes the node for a freshly mapped chunk of `size` bytes starting at `address`.
+    unsafe fn init(address: NonNull<u8>, size: usize) -> NonNull<Node> {
         let node_ptr: NonNull<Node> = address.cast();
 
         let header = AvlHeader {
-            size: page_aligned_layout.size() - size_of::<AvlHeader>(),
+            size,
             height: 1,
             left: None,
             right: None,
+            next: None,
+            prev: None,
+            prev_size: 0,
+            free: false,
+            last: true,
         };
 
-        // Calculate data pointer
-        let data_ptr = address.as_ptr().add(offset);
-
-        // Write node to memory
+        // Write node to memory, the data pointer is filled in once the chunk is handed out
         node_ptr.as_ptr().write(Node {
             header,
-            data: data_ptr
+            data: core::ptr::null_mut(),
         });
 
         node_ptr
     }
 
+    /// Lays the data for `layout` out in the chunk, see `chunk::place_data`. A guarded chunk has
+    /// it at the end, see `chunk::place_data_at_end`.
+    unsafe fn place_data(mut node: NonNull<Node>, layout: Layout) -> *mut u8 {
+        let data = if GUARD_PAGES {
+            chunk::place_data_at_end(node.cast(), size_of::<Node>(), layout)
+        } else {
+            chunk::place_data(node.cast(), size_of::<Node>(), layout)
+        };
+        node.as_mut().data = data;
+        data
+    }
+
+    /// The chunk `ptr` was placed in by `place_data`.
+    unsafe fn from_data(ptr: *mut u8) -> NonNull<Node> {
+        chunk::from_data(ptr).cast()
+    }
+
+    /// Cuts the chunk short at `at` bytes, which has to be a page boundary inside it, and turns
+    /// the rest into a free chunk of its own. Returns the new chunk's node.
+    unsafe fn split(mut node: NonNull<Node>, at: usize) -> NonNull<Node> {
+        let header = &mut node.as_mut().header;
+        debug_assert!(at.is_multiple_of(PAGE_SIZE) && at < header.size);
+
+        let tail: NonNull<Node> = node.cast::<u8>().add(at).cast();
+        tail.as_ptr().write(Node {
+            header: AvlHeader {
+                size: header.size - at,
+                height: 1,
+                left: None,
+                right: None,
+                next: None,
+                prev: None,
+                prev_size: at,
+                free: true,
+                last: header.last,
+            },
+            data: core::ptr::null_mut(),
+        });
+        header.size = at;
+        header.last = false;
+        Self::update_next(tail);
+
+        tail
+    }
+
+    /// The chunk right after this one in the same mapping.
+    unsafe fn next_chunk(node: NonNull<Node>) -> NodePtr {
+        let header = &node.as_ref().header;
+        if header.last {
+            None
+        } else {
+            Some(node.cast::<u8>().add(header.size).cast())
+        }
+    }
+
+    /// The chunk right before this one in the same mapping.
+    unsafe fn prev_chunk(node: NonNull<Node>) -> NodePtr {
+        match node.as_ref().header.prev_size {
+            0 => None,
+            prev_size => Some(node.cast::<u8>().sub(prev_size).cast()),
+        }
+    }
+
+    /// Tells the following chunk how far back this one starts, after its size changed.
+    unsafe fn update_next(node: NonNull<Node>) {
+        if let Some(mut next) = Self::next_chunk(node) {
+            next.as_mut().header.prev_size = node.as_ref().header.size;
+        }
+    }
+
+    /// Whether the chunk has its mapping to itself, nothing split off it and nothing merged in.
+    fn is_whole_mapping(&self) -> bool {
+        self.header.prev_size == 0 && self.header.last
+    }
+
+    /// Resizes a chunk that is its whole mapping so its data can hold `new_size` bytes, returning
+    /// where the data ended up. None if the kernel refused, the chunk is unchanged then.
+    unsafe fn remap(
+        node: NonNull<Node>,
+        layout: Layout,
+        new_size: usize,
+        counters: &mut MapCounters,
+    ) -> Option<*mut u8> {
+        let old_size = node.as_ref().header.size;
+        let offset = node.as_ref().data as usize - node.as_ptr() as usize;
+        let size = offset.checked_add(new_size)?.checked_next_multiple_of(PAGE_SIZE)?;
+        if size == old_size {
+            return Some(node.as_ref().data);
+        }
+
+        // past a page the data's alignment depends on where the mapping starts, so it can only be
+        // resized where it is
+        let may_move = layout.align() <= PAGE_SIZE;
+        let mut node: NonNull<Node> = resize_memory(node.cast(), old_size, size, may_move).ok()?.cast();
+        counters.remapped(old_size, size);
+
+        let data = node.cast::<u8>().as_ptr().add(offset);
+        node.as_mut().header.size = size;
+        node.as_mut().data = data;
+        Some(data)
+    }
+
+    /// Bytes from the data to the end of the chunk.
+    fn capacity(&self) -> usize {
+        self.header.size - (self.data as usize - self as *const Node as usize)
+    }
+
     fn height(node: NodePtr) -> i32 {
         node.map_or(0, |node| unsafe { node.as_ref().header.height })
     }
@@ -123,12 +266,12 @@ impl Node {
         let balance = ptr.as_ref().balance_factor();
 
         if balance > 1 {
-            if ptr.as_ref().header.left.map_or(false, |left| left.as_ref().balance_factor() < 0) {
+            if ptr.as_ref().header.left.is_some_and(|left| left.as_ref().balance_factor() < 0) {
                 ptr.as_mut().header.left = Some(Self::rotate_left(&mut ptr.as_ref().header.left.unwrap()));
             }
             Self::rotate_right(ptr)
         } else if balance < -1 {
-            if ptr.as_ref().header.right.map_or(false, |right| right.as_ref().balance_factor() > 0) {
+            if ptr.as_ref().header.right.is_some_and(|right| right.as_ref().balance_factor() > 0) {
                 ptr.as_mut().header.right = Some(Self::rotate_right(&mut ptr.as_ref().header.right.unwrap()));
             }
             Self::rotate_left(ptr)
@@ -137,176 +280,468 @@ impl Node {
         }
     }
 
-    unsafe fn get_min(node: NonNull<Node>, parent: NonNull<Node>) -> (NonNull<Node>, NonNull<Node>) {
-        let mut current = node;
-        let mut parent = parent;
-        while let Some(left) = current.as_ref().header.left {
-            parent = current;
-            current = left;
+    /// Unlinks the smallest node of the subtree, returns the new root of the subtree and the node.
+    unsafe fn remove_min(mut node: NonNull<Node>) -> (NodePtr, NonNull<Node>) {
+        match node.as_ref().header.left {
+            None => (node.as_mut().header.right.take(), node),
+            Some(left) => {
+                let (left, min) = Self::remove_min(left);
+                node.as_mut().header.left = left;
+                (Some(Self::rebalance(&mut node)), min)
+            }
+        }
+    }
+}
+
+/// In order iterator over the free chunks of an `AVLTree`, yielding where each chunk starts and
+/// its length. Chunks of the same size come out one after another. It keeps its path through the
+/// tree in a fixed array, so walking the tree never allocates.
+pub struct FreeChunks<'a> {
+    stack: [NodePtr; MAX_HEIGHT],
+    depth: usize,
+    /// the next chunk in the bucket of the node yielded last
+    bucket: NodePtr,
+    _tree: PhantomData<&'a AVLTree>,
+}
+
+impl FreeChunks<'_> {
+    fn push_left(&mut self, mut node: NodePtr) {
+        while let Some(current) = node {
+            self.stack[self.depth] = Some(current);
+            self.depth += 1;
+            node = unsafe { current.as_ref().header.left };
         }
-        (current, parent)
     }
+}
 
+impl Iterator for FreeChunks<'_> {
+    type Item = (NonNull<u8>, usize);
 
-    fn swap_header_details(&mut self, other: &mut Node) {
-        swap(&mut self.header.left, &mut other.header.left);
-        swap(&mut self.header.right, &mut other.header.right);
-        swap(&mut self.header.height, &mut other.header.height);
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = match self.bucket {
+            Some(node) => node,
+            None => {
+                self.depth = self.depth.checked_sub(1)?;
+                let node = self.stack[self.depth]?;
+                self.push_left(unsafe { node.as_ref().header.right });
+                node
+            }
+        };
+
+        let header = unsafe { &node.as_ref().header };
+        self.bucket = header.next;
+        Some((node.cast(), header.size))
     }
+}
 
+impl Default for AVLTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Unmaps every cached free chunk.
+impl Drop for AVLTree {
+    fn drop(&mut self) {
+        self.trim();
+    }
 }
 
 impl AVLTree {
-    fn new() -> Self {
-        AVLTree { root: None }
+    pub const fn new() -> Self {
+        Self::with_max_cached_bytes(DEFAULT_MAX_CACHED_BYTES)
     }
 
-    fn insert_node(&mut self, value: NonNull<Node>) {
-        let root= self.reinsert_node(self.root, value);
-        self.root = Some(root);
+    /// A tree that unmaps chunks once more than `max_cached_bytes` are free, zero gives every
+    /// chunk back as soon as it's freed.
+    pub const fn with_max_cached_bytes(max_cached_bytes: usize) -> Self {
+        AVLTree { root: None, cached_bytes: 0, max_cached_bytes, counters: MapCounters::new() }
     }
 
-    fn remove(&mut self, value: usize) -> NodePtr {
-        if let Some(node) = self.root {
-            unsafe { self.remove_node(node, value, Root) }
-        } else {
-            None
+    pub fn max_cached_bytes(&self) -> usize {
+        self.max_cached_bytes
+    }
+
+    pub fn set_max_cached_bytes(&mut self, max_cached_bytes: usize) {
+        self.max_cached_bytes = max_cached_bytes;
+        self.release_excess();
+        self.debug_validate();
+    }
+
+    /// Bytes sitting free in the tree.
+    pub fn cached_bytes(&self) -> usize {
+        self.cached_bytes
+    }
+
+    /// Checks every invariant the tree relies on and returns the first one found broken: sizes
+    /// in order, heights correct and balanced, buckets linked both ways, every chunk marked free
+    /// with boundary tags agreeing with its neighbours and no two free neighbours left unmerged,
+    /// and `cached_bytes` adding up. A node reachable twice breaks the ordering or a bucket's
+    /// links, so that's caught too. It walks the whole tree, so it's meant for tests and
+    /// debugging.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let (_, bytes) = unsafe { Self::validate_subtree(self.root, 0, usize::MAX, 0)? };
+        if bytes != self.cached_bytes {
+            return Err("cached_bytes doesn't match the free chunks");
         }
+        Ok(())
     }
 
-    unsafe fn swap_nodes(
-        &mut self,
-        mut target_node: NonNull<Node>,
-        mut successor_node: NonNull<Node>,
-        mut successor_parent: NonNull<Node>,
-        parent: SearchDirection
-    ) -> DeleteAction
-    {
-        // in all cases swap the meta data, height, left pointer, right pointer, and make parent
-        // point at successor
-        target_node.as_mut().swap_header_details(&mut successor_node.as_mut());
-        match parent {
-            Left(mut left) => left.as_mut().header.left = Some(successor_node),
-            Right(mut right) => right.as_mut().header.right = Some(successor_node),
-            Root => self.root = Some(successor_node),
-        }
-
-        // println!("{}", successor_node.as_ref());
-        // println!("suc right: {} suc left: {}", successor_node.as_ref().header.right.unwrap().as_ref(), successor_node.as_ref().header.left.unwrap().as_ref());
-
-        // remove self loop
-        if successor_parent == target_node {
-            // stop the recursive loop formed here
-            if successor_node.as_mut().header.right == Some(successor_node) {
-                successor_node.as_mut().header.right = None;
-            } else {
-                successor_node.as_mut().header.left = None;
+    /// Panics if the tree is broken, when the `validate` feature is on in a debug build.
+    fn debug_validate(&self) {
+        if cfg!(all(debug_assertions, feature = "validate")) {
+            if let Err(error) = self.validate() {
+                panic!("AVL tree is broken: {error}");
             }
+        }
+    }
 
-            NoAction
-        } else {
-            // make successor parent point to target
-            // todo: prove that it's always going to be pointing left and remove a pointless
-            // if check
-            if successor_parent.as_mut().header.right == Some(successor_node) {
-                successor_parent.as_mut().header.right = Some(target_node);
+    /// Checks a subtree whose sizes all have to lie strictly between `above` and `below`,
+    /// returning its height and the total size of its chunks.
+    unsafe fn validate_subtree(
+        node: NodePtr,
+        above: usize,
+        below: usize,
+        depth: usize,
+    ) -> Result<(i32, usize), &'static str> {
+        let Some(node) = node else {
+            return Ok((0, 0));
+        };
+        if depth >= MAX_HEIGHT {
+            return Err("the tree is taller than an AVL tree can be");
+        }
+
+        let header = &node.as_ref().header;
+        if header.size <= above || header.size >= below {
+            return Err("sizes are out of order, or a node is reachable twice");
+        }
+
+        let (left_height, left_bytes) = Self::validate_subtree(header.left, above, header.size, depth + 1)?;
+        let (right_height, right_bytes) = Self::validate_subtree(header.right, header.size, below, depth + 1)?;
+        if header.height != max(left_height, right_height) + 1 {
+            return Err("a node's height is stale");
+        }
+        if (left_height - right_height).abs() > 1 {
+            return Err("a node is out of balance");
+        }
+
+        let bucket_bytes = Self::validate_bucket(node)?;
+        Ok((header.height, left_bytes + right_bytes + bucket_bytes))
+    }
+
+    /// Checks a node in the tree along with every chunk in its bucket, returning their total size.
+    unsafe fn validate_bucket(node: NonNull<Node>) -> Result<usize, &'static str> {
+        let size = node.as_ref().header.size;
+        let mut bytes = 0;
+        let mut prev = None;
+        let mut current = Some(node);
+
+        while let Some(chunk) = current {
+            let header = &chunk.as_ref().header;
+            if header.prev != prev {
+                return Err("a bucket's links disagree, or a chunk is reachable twice");
+            }
+            if header.size != size {
+                return Err("a chunk is in the bucket for another size");
+            }
+            Self::validate_chunk(chunk)?;
+
+            bytes += size;
+            prev = current;
+            current = header.next;
+        }
+        Ok(bytes)
+    }
+
+    /// Checks a free chunk against its neighbours in memory.
+    unsafe fn validate_chunk(node: NonNull<Node>) -> Result<(), &'static str> {
+        let header = &node.as_ref().header;
+        if !header.free {
+            return Err("a chunk in the tree isn't marked free");
+        }
+        if header.size == 0 || !header.size.is_multiple_of(PAGE_SIZE) {
+            return Err("a chunk isn't a whole number of pages");
+        }
+
+        if let Some(next) = Node::next_chunk(node) {
+            let next_header = &next.as_ref().header;
+            if next_header.prev_size != header.size {
+                return Err("the chunk after a free chunk has the wrong prev_size");
+            }
+            if next_header.free {
+                return Err("a free chunk wasn't merged with the free chunk after it");
+            }
+        }
+        if let Some(prev) = Node::prev_chunk(node) {
+            let prev_header = &prev.as_ref().header;
+            if prev_header.size != header.prev_size || prev_header.last {
+                return Err("the chunk before a free chunk doesn't end where it starts");
+            }
+            if prev_header.free {
+                return Err("a free chunk wasn't merged with the free chunk before it");
+            }
+        }
+        Ok(())
+    }
+
+    /// Every free chunk in the tree from smallest to largest, see `FreeChunks`.
+    pub fn free_chunks(&self) -> FreeChunks<'_> {
+        let mut chunks = FreeChunks {
+            stack: [None; MAX_HEIGHT],
+            depth: 0,
+            bucket: None,
+            _tree: PhantomData,
+        };
+        chunks.push_left(self.root);
+        chunks
+    }
+
+    /// Calls `visit` with the start and length of every free chunk, smallest first. Neither this
+    /// nor `free_chunks` allocates, so either can run inside the global allocator.
+    pub fn for_each_free_chunk(&self, mut visit: impl FnMut(NonNull<u8>, usize)) {
+        for (address, size) in self.free_chunks() {
+            visit(address, size);
+        }
+    }
+
+    /// Unmaps the largest free chunks until the tree is back under its cap. Largest first gives
+    /// the most memory back per syscall, and keeps the smaller chunks that are more likely to be
+    /// reused.
+    fn release_excess(&mut self) {
+        while self.cached_bytes > self.max_cached_bytes {
+            match self.remove_largest() {
+                Some(node) => unsafe { self.release(node) },
+                None => break,
+            }
+        }
+    }
+
+    /// Unmaps a chunk that has already been taken out of the tree.
+    unsafe fn release(&mut self, node: NonNull<Node>) {
+        // free neighbours would have been merged with the chunk, so both are in use and become
+        // the ends of their own runs
+        if let Some(mut prev) = Node::prev_chunk(node) {
+            prev.as_mut().header.last = true;
+        }
+        if let Some(mut next) = Node::next_chunk(node) {
+            next.as_mut().header.prev_size = 0;
+        }
+
+        let size = node.as_ref().header.size;
+        self.cached_bytes -= size;
+        release_memory(node.cast(), size);
+        self.counters.unmapped(size);
+    }
+
+    /// Merges a chunk that's just been freed with whichever of its neighbours are free too,
+    /// taking them out of the tree. Returns the merged chunk, which isn't in the tree yet.
+    unsafe fn coalesce(&mut self, mut node: NonNull<Node>) -> NonNull<Node> {
+        if let Some(next) = Node::next_chunk(node).filter(|next| next.as_ref().header.free) {
+            self.unlink(next);
+            self.cached_bytes -= next.as_ref().header.size;
+
+            let header = &mut node.as_mut().header;
+            header.size += next.as_ref().header.size;
+            header.last = next.as_ref().header.last;
+            if poison::ENABLED {
+                poison::fill(next.as_ptr().cast(), 0, size_of::<Node>());
             }
-            if successor_parent.as_mut().header.left == Some(successor_node) {
-                successor_parent.as_mut().header.left = Some(target_node);
+        }
+
+        if let Some(mut prev) = Node::prev_chunk(node).filter(|prev| prev.as_ref().header.free) {
+            self.unlink(prev);
+            self.cached_bytes -= prev.as_ref().header.size;
+
+            let header = &mut prev.as_mut().header;
+            header.size += node.as_ref().header.size;
+            header.last = node.as_ref().header.last;
+            if poison::ENABLED {
+                poison::fill(node.as_ptr().cast(), 0, size_of::<Node>());
             }
-            SearchDelete
+            node = prev;
         }
 
+        Node::update_next(node);
+        node
+    }
+
+    /// Merges a chunk with any free neighbours and puts the result back in the tree.
+    unsafe fn free_chunk(&mut self, node: NonNull<Node>) {
+        if poison::ENABLED {
+            poison::fill(node.as_ptr().cast(), size_of::<Node>(), node.as_ref().header.size);
+        }
+        let node = self.coalesce(node);
+        let size = node.as_ref().header.size;
+        self.insert_node(node);
+        self.cached_bytes += size;
+        self.release_excess();
+        self.debug_validate();
     }
 
-    /// Delete the node by removing pointers pointing to it. this function returns a DeleteAction
-    /// so the caller knows whether or not it needs to search through the tree and delete again
-    /// in the case of a swap between the node being deleted, and the next in order node in the
-    /// tree.
-    unsafe fn delete_node(&mut self, mut root_node: NonNull<Node>, parent: SearchDirection) -> DeleteAction {
-        let ref_node = root_node.as_mut();
-        if ref_node.header.left.is_none() && ref_node.header.right.is_none() {
-            // with no children return true, and the level above deletes this node when unwinding
-            // the recursive stack
-            match parent {
-                Left(mut node) => node.as_mut().header.left = None,
-                Right(mut node) => node.as_mut().header.right = None,
-                Root => self.root = None
+    /// Takes a particular free chunk out of the tree, wherever it sits.
+    unsafe fn unlink(&mut self, mut node: NonNull<Node>) {
+        let header = &mut node.as_mut().header;
+
+        if let Some(mut prev) = header.prev {
+            // somewhere down a bucket, the tree itself is untouched
+            prev.as_mut().header.next = header.next;
+            if let Some(mut next) = header.next {
+                next.as_mut().header.prev = Some(prev);
             }
-            NoAction
-        } else if ref_node.header.left.is_some() && ref_node.header.right.is_some() {
-            // with both children swap this node and the minimum node on the right hand side
-            // todo: find a better way of doing this, way too many pointers
+            return;
+        }
 
-            // safe because of if check
-            let right_child = ref_node.header.right.unwrap();
-            let (successor, successor_parent) = Node::get_min(right_child, root_node);
+        match header.next {
+            // the only chunk of its size, so removing by size removes this one
+            None => {
+                let (root, removed) = Self::remove_node(self.root.unwrap(), header.size);
+                debug_assert_eq!(removed, Some(node));
+                self.root = root;
+            }
+            // the next chunk in the bucket takes its place in the tree
+            Some(mut successor) => {
+                let successor_header = &mut successor.as_mut().header;
+                successor_header.prev = None;
+                successor_header.height = header.height;
+                successor_header.left = header.left;
+                successor_header.right = header.right;
+                self.replace_node(node, successor);
+            }
+        }
+    }
 
-            self.swap_nodes(root_node, successor, successor_parent, parent)
-        } else {
-            // A single child node remains, swap it and then remove the node
-            let successor = root_node.as_mut().header.left.or(root_node.as_mut().header.right).unwrap();
-            self.swap_nodes(root_node, successor, root_node, parent)
+    /// Points whatever links to `node` in the tree at `replacement` instead.
+    unsafe fn replace_node(&mut self, node: NonNull<Node>, replacement: NonNull<Node>) {
+        let size = node.as_ref().header.size;
+        let mut link = &mut self.root;
+        while let Some(mut current) = *link {
+            if current == node {
+                *link = Some(replacement);
+                return;
+            }
+            let header = &mut current.as_mut().header;
+            link = if size < header.size { &mut header.left } else { &mut header.right };
         }
+        unreachable!("the node isn't in the tree");
+    }
+
+    fn remove_largest(&mut self) -> NodePtr {
+        let mut largest = self.root?;
+        while let Some(right) = unsafe { largest.as_ref().header.right } {
+            largest = right;
+        }
+        self.remove(unsafe { largest.as_ref().header.size })
+    }
+
+    fn insert_node(&mut self, mut value: NonNull<Node>) {
+        // a node coming back from the application still carries its header from when it was last
+        // in the tree
+        let header = unsafe { &mut value.as_mut().header };
+        header.height = 1;
+        header.left = None;
+        header.right = None;
+        header.next = None;
+        header.prev = None;
+        header.free = true;
+
+        let root= self.reinsert_node(self.root, value);
+        self.root = Some(root);
+    }
+
+    /// Removes the best fit for `value`, the smallest node that is at least as large.
+    fn remove(&mut self, value: usize) -> NodePtr {
+        // find the best fit up front, so the removal below always deletes an exact match instead
+        // of handing back a node that is still linked into the tree
+        let mut best = self.lower_bound(value)?;
+
+        // a chunk from the bucket can be handed out without touching the tree
+        let header = unsafe { &mut best.as_mut().header };
+        let removed = if let Some(duplicate) = header.next {
+            header.next = unsafe { duplicate.as_ref().header.next };
+            if let Some(mut next) = header.next {
+                unsafe { next.as_mut().header.prev = Some(best) };
+            }
+            duplicate
+        } else {
+            let (root, removed) = unsafe { Self::remove_node(self.root?, header.size) };
+            self.root = root;
+            removed?
+        };
+
+        unsafe { (*removed.as_ptr()).header.free = false };
+        Some(removed)
     }
 
-    /// starting from root, search until the appropriate node is found, and then remove. A parent
-    /// is provided as this allocator depends on chunks of memory being contiguous; a pointer to
-    /// data MUST be the size of an AVLHeader in front of the beginning of the AVLHeader. In other
-    /// words, it will cause serious issues if a complete swap doesn't occur upon an end user
-    /// calling free() upon some data. For this reason if the parent is None, it's the root node.
-    ///
-    /// This takes the lower bound of a size in order to implement a best fit approach.
-    unsafe fn remove_node(&mut self,
-                          mut root_node: NonNull<Node>,
-                          size: usize,
-                          parent: SearchDirection) -> NodePtr
-    {
-        let ref_node = root_node.as_mut();
+    /// The smallest node that can hold `value`.
+    fn lower_bound(&self, value: usize) -> NodePtr {
+        let mut current = self.root;
+        let mut best = None;
+        while let Some(node) = current {
+            let header = unsafe { &node.as_ref().header };
+            if header.size >= value {
+                best = Some(node);
+                current = header.left;
+            } else {
+                current = header.right;
+            }
+        }
+        best
+    }
 
-        let mut header = &mut ref_node.header;
-        let result;
+    /// Unlinks the node of exactly `size` from the subtree and returns the new root of the subtree
+    /// along with the removed node. A node's header has to stay in front of its data, so nodes are
+    /// never copied around; when the removed node has two children its in order successor is
+    /// relinked into its place instead.
+    unsafe fn remove_node(mut node: NonNull<Node>, size: usize) -> (NodePtr, NodePtr) {
+        let header = &mut node.as_mut().header;
+        let removed;
 
         match size.cmp(&header.size) {
             Ordering::Less => {
-                if let Some(node) = header.left {
-                    result = self.remove_node(node, size, Left(root_node))
-                } else {
-                    // at this point no better fit exists
-                    result = Some(root_node);
-                }
+                let (left, result) = header.left.map_or((None, None), |left| Self::remove_node(left, size));
+                header.left = left;
+                removed = result;
             }
             Ordering::Greater => {
-                if let Some(node) = header.right {
-                    result = self.remove_node(node, size, Right(root_node))
-                } else {
-                    result = None;
-                }
+                let (right, result) = header.right.map_or((None, None), |right| Self::remove_node(right, size));
+                header.right = right;
+                removed = result;
             }
             Ordering::Equal => {
-                result = Some(root_node);
-                let right_child = ref_node.header.right;
-                let delete_action = self.delete_node(root_node, parent);
-                match delete_action {
-                    // because of tree guarantees, look right. This is important for correctness
-                    // as swapping with the successor will force the search to fail. This call
-                    // has to be done here for rebalancing logic
-                    SearchDelete => {
-                        self.remove_node(right_child.unwrap(), size, parent);
-
-                        // update the root node in the stack after recursive delete
-                        match parent {
-                            Left(parent) => root_node = parent,
-                            Right(parent) => root_node = parent,
-                            Root => root_node = self.root.unwrap(),
-                        }
-                    },
-                    _ => {},
-                }
+                let mut successor = match (header.left.take(), header.right.take()) {
+                    (None, child) | (child, None) => return (child, Some(node)),
+                    (left, Some(right)) => {
+                        let (right, mut successor) = Node::remove_min(right);
+                        successor.as_mut().header.left = left;
+                        successor.as_mut().header.right = right;
+                        successor
+                    }
+                };
+                return (Some(Node::rebalance(&mut successor)), Some(node));
             }
         }
-        Node::rebalance(&mut root_node);
-        result
+
+        (Some(Node::rebalance(&mut node)), removed)
+    }
+
+    /// Moves an allocation to a new chunk of `new_size` bytes, freeing the old one.
+    unsafe fn move_data(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let Ok(new_layout) = Layout::from_size_align(new_size, layout.align()) else {
+            return core::ptr::null_mut();
+        };
+        // the new block has to come from this tree, dealloc can only take back its own chunks
+        let new_ptr = self.alloc(new_layout);
+
+        if new_ptr.is_null() {
+            return core::ptr::null_mut(); // Return null on allocation failure.
+        }
+
+        // Copy the existing data to the new location.
+        core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+
+        self.dealloc(ptr);
+
+        new_ptr
     }
 
     fn reinsert_node(&mut self, node: NodePtr, value: NonNull<Node>) -> NonNull<Node> {
@@ -318,11 +753,19 @@ impl AVLTree {
                 match value_ref.header.size.cmp(&node_ref.header.size) {
                     Ordering::Less => node_ref.header.left = Some(self.reinsert_node(node_ref.header.left, value)),
                     Ordering::Greater => node_ref.header.right = Some(self.reinsert_node(node_ref.header.right, value)),
-                    // todo: handle this case, could be a linked list of nodes potentially, not sure, but it seems likely
-                    // that in a real malloc implementation there'd be multiple chunks the same size
-                    // perhaps a non stupid implementation involves calling munmap here and letting
-                    // the os deal with it?
-                    Ordering::Equal => return ptr,
+                    // the size is already in the tree, so the chunk joins that node's bucket and
+                    // the shape of the tree doesn't change
+                    Ordering::Equal => {
+                        let bucket = node_ref.header.next.replace(value);
+                        unsafe {
+                            (*value.as_ptr()).header.next = bucket;
+                            (*value.as_ptr()).header.prev = Some(ptr);
+                            if let Some(next) = bucket {
+                                (*next.as_ptr()).header.prev = Some(value);
+                            }
+                        }
+                        return ptr;
+                    }
                 }
                 unsafe { Node::rebalance(&mut ptr) }
             }
@@ -331,57 +774,399 @@ impl AVLTree {
 }
 
 unsafe impl LargeAllocator for AVLTree {
-    // todo: I should consider making this more robust, I could have node creation return a result
-    // of AllocError from nightly and then on that return a null ptr
     unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.remove(layout.size()) {
-            None => {
-                let node = Node::new(layout);
-                node.as_ref().data
-            }
+        if GUARD_PAGES {
+            return match Node::new(layout, &mut self.counters) {
+                Some(node) => Node::place_data(node, layout),
+                None => core::ptr::null_mut(),
+            };
+        }
+
+        let Some(size) = Node::chunk_size(layout) else {
+            return core::ptr::null_mut();
+        };
+
+        let node = match self.remove(size) {
+            None => match Node::new(layout, &mut self.counters) {
+                Some(node) => node,
+                None => return core::ptr::null_mut(),
+            },
             Some(node) => {
-                node.as_ref().data
+                self.cached_bytes -= node.as_ref().header.size;
+                // only what's about to be handed out, a tail split off stays poisoned until it's
+                // handed out itself
+                if poison::ENABLED {
+                    poison::check(node.as_ptr().cast(), size_of::<Node>(), size);
+                }
+                node
             }
+        };
+        let data = Node::place_data(node, layout);
+
+        // a best fit can still be far bigger than the request, give back the pages past the end
+        // of the data so they can serve other requests
+        let used = (data as usize - node.as_ptr() as usize + layout.size()).next_multiple_of(PAGE_SIZE);
+        if used < node.as_ref().header.size {
+            let tail = Node::split(node, used);
+            self.cached_bytes += tail.as_ref().header.size;
+            self.insert_node(tail);
         }
+
+        self.debug_validate();
+        data
     }
 
     unsafe fn dealloc(&mut self, ptr: *mut u8) {
         assert!(!ptr.is_null(), "Attempted to deallocate a null pointer.");
 
-        // walk backwards, get the data required
-        let address = ptr.sub(size_of::<AvlHeader>());
-
-        // this already has been aligned
-        let node: NonNull<Node> = *address.cast();
-
-        // put the mmapped memory back in the tree
-        self.insert_node(node);
+        // walk backwards to the chunk's node
+        let node = Node::from_data(ptr);
+        if GUARD_PAGES {
+            return chunk::unmap_guarded(node.cast(), node.as_ref().header.size, &mut self.counters);
+        }
+        self.free_chunk(node);
     }
+
     unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         assert!(!ptr.is_null(), "Attempted to reallocate a null pointer.");
 
-        // walk backwards, get the data required
-        let address = ptr.sub(size_of::<AvlHeader>());
+        // walk backwards to the chunk's node
+        let node = Node::from_data(ptr);
 
-        // this already has been aligned
-        let node: NonNull<Node> = *address.cast();
+        // a guarded chunk always moves, its data has to keep ending at the guard page
+        if GUARD_PAGES {
+            return self.move_data(ptr, layout, new_size);
+        }
 
-        // todo: Should I get a chunk here if necessary? I'm leaning on virtual memory here
-        if node.as_ref().header.size >= new_size {
+        // the kernel can grow or shrink a chunk that came straight from mmap without copying it
+        if node.as_ref().is_whole_mapping() {
+            if let Some(data) = Node::remap(node, layout, new_size, &mut self.counters) {
+                return data;
+            }
+        }
+
+        if node.as_ref().capacity() >= new_size {
+            // the pages past the new end go back to the tree, where they can serve other requests
+            // or be unmapped
+            let used = (ptr as usize - node.as_ptr() as usize + new_size).next_multiple_of(PAGE_SIZE);
+            if used < node.as_ref().header.size {
+                self.free_chunk(Node::split(node, used));
+            }
             return ptr;
         }
 
-        let new_layout = Layout::from_size_align(new_size, layout.align()).unwrap();
-        let new_ptr = alloc(new_layout);
+        self.move_data(ptr, layout, new_size)
+    }
 
-        if new_ptr.is_null() {
-            return std::ptr::null_mut(); // Return null on allocation failure.
+    unsafe fn usable_size(&self, ptr: *mut u8, _layout: Layout) -> usize {
+        Node::from_data(ptr).as_ref().capacity()
+    }
+
+    fn trim(&mut self) {
+        while let Some(node) = self.remove_largest() {
+            unsafe { self.release(node) };
         }
+        self.debug_validate();
+    }
 
-        // Copy the existing data to the new location.
-        std::ptr::copy_nonoverlapping(ptr, new_ptr, node.as_ref().header.size);
+    fn stats(&self) -> Stats {
+        self.counters.stats(self.cached_bytes)
+    }
+}
 
-        self.dealloc(ptr);
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
 
-        new_ptr
-    }}
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        /// free a chunk this many pages long
+        Insert(usize),
+        /// take the best fit for this many pages
+        Remove(usize),
+        /// take the best fit for the root's size
+        RemoveRoot,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        // few distinct sizes, so buckets fill up and removals often hit an exact match
+        prop_oneof![
+            3 => (1..24usize).prop_map(Op::Insert),
+            2 => (1..24usize).prop_map(Op::Remove),
+            1 => Just(Op::RemoveRoot),
+        ]
+    }
+
+    /// A layout whose chunk is exactly `pages` pages, the header fits in the half page left over.
+    fn pages(pages: usize) -> Layout {
+        Layout::from_size_align(pages * PAGE_SIZE - PAGE_SIZE / 2, 8).unwrap()
+    }
+
+    /// Maps a chunk of `pages` pages, ready to go into the tree.
+    unsafe fn chunk(tree: &mut AVLTree, pages: usize) -> NonNull<Node> {
+        let layout = self::pages(pages);
+        // always an ordinary chunk, even with guard pages on, as that's what the tree holds
+        let (address, size) = chunk::map(size_of::<Node>(), layout, &mut tree.counters).unwrap();
+        let node = Node::init(address, size);
+        assert_eq!(node.as_ref().header.size, pages * PAGE_SIZE);
+        node
+    }
+
+    /// Takes the best fit for `size` out of the model.
+    fn model_remove(model: &mut BTreeMap<usize, usize>, size: usize) -> Option<usize> {
+        let (&size, count) = model.range_mut(size..).next()?;
+        *count -= 1;
+        if *count == 0 {
+            model.remove(&size);
+        }
+        Some(size)
+    }
+
+    proptest! {
+        #[test]
+        fn matches_btreemap(ops in prop::collection::vec(op(), 1..200)) {
+            let mut tree = AVLTree::with_max_cached_bytes(usize::MAX);
+            // size to number of free chunks of that size
+            let mut model = BTreeMap::new();
+
+            for op in ops {
+                unsafe {
+                    match op {
+                        Op::Insert(pages) => {
+                            let node = chunk(&mut tree, pages);
+                            tree.insert_node(node);
+                            tree.cached_bytes += pages * PAGE_SIZE;
+                            *model.entry(pages * PAGE_SIZE).or_insert(0) += 1;
+                        }
+                        Op::Remove(pages) => {
+                            let removed = tree.remove(pages * PAGE_SIZE);
+                            let size = removed.map(|node| node.as_ref().header.size);
+                            prop_assert_eq!(size, model_remove(&mut model, pages * PAGE_SIZE));
+                            if let Some(node) = removed {
+                                tree.release(node);
+                            }
+                        }
+                        Op::RemoveRoot => {
+                            let size = tree.root.map_or(0, |root| root.as_ref().header.size);
+                            let removed = tree.remove(size);
+                            prop_assert_eq!(removed.map(|node| node.as_ref().header.size), model_remove(&mut model, size));
+                            if let Some(node) = removed {
+                                tree.release(node);
+                            }
+                        }
+                    }
+                }
+
+                prop_assert_eq!(tree.validate(), Ok(()));
+                let sizes: Vec<_> = tree.free_chunks().map(|(_, size)| size).collect();
+                let expected: Vec<_> = model.iter().flat_map(|(&size, &count)| std::iter::repeat_n(size, count)).collect();
+                prop_assert_eq!(sizes, expected);
+            }
+
+            tree.trim();
+            prop_assert_eq!(tree.cached_bytes(), 0);
+            prop_assert_eq!(tree.stats().mapped_bytes, 0);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(feature = "guard-pages", ignore = "guarded chunks are never cached")]
+    fn largest_chunks_are_evicted_past_the_cap() {
+        let mut tree = AVLTree::with_max_cached_bytes(12 * PAGE_SIZE);
+        unsafe {
+            // separate mappings, so nothing merges when they're freed
+            let [two, four, eight, sixteen] = [2, 4, 8, 16].map(|count| tree.alloc(pages(count)));
+            assert_eq!(tree.stats().mmap_calls, 4);
+
+            // too big for the cap on its own
+            tree.dealloc(sixteen);
+            assert_eq!((tree.stats().munmap_calls, tree.cached_bytes()), (1, 0));
+
+            tree.dealloc(two);
+            tree.dealloc(eight);
+            assert_eq!((tree.stats().munmap_calls, tree.cached_bytes()), (1, 10 * PAGE_SIZE));
+
+            // 14 pages is over, the 8 page chunk goes rather than the one just freed
+            tree.dealloc(four);
+            assert_eq!((tree.stats().munmap_calls, tree.cached_bytes()), (2, 6 * PAGE_SIZE));
+            let sizes: Vec<_> = tree.free_chunks().map(|(_, size)| size).collect();
+            assert_eq!(sizes, [2 * PAGE_SIZE, 4 * PAGE_SIZE]);
+        }
+
+        // lowering the cap evicts straight away
+        tree.set_max_cached_bytes(3 * PAGE_SIZE);
+        assert_eq!((tree.stats().munmap_calls, tree.cached_bytes()), (3, 2 * PAGE_SIZE));
+        tree.set_max_cached_bytes(0);
+        assert_eq!((tree.stats().munmap_calls, tree.cached_bytes()), (4, 0));
+        assert_eq!(tree.stats().mapped_bytes, 0);
+    }
+
+    #[test]
+    #[cfg_attr(feature = "guard-pages", ignore = "guarded chunks are never split")]
+    fn split_pieces_merge_back() {
+        let mut tree = AVLTree::new();
+        unsafe {
+            let whole = tree.alloc(pages(16));
+            let start = Node::from_data(whole);
+            tree.dealloc(whole);
+
+            // five 3 page pieces cut one after another off the front, one page left over
+            let pieces: Vec<_> = (0..5).map(|_| tree.alloc(pages(3))).collect();
+            for (index, &piece) in pieces.iter().enumerate() {
+                assert_eq!(Node::from_data(piece).cast::<u8>(), start.cast::<u8>().add(index * 3 * PAGE_SIZE));
+            }
+            assert_eq!(tree.cached_bytes(), PAGE_SIZE);
+
+            for index in [2, 0, 4, 1, 3] {
+                tree.dealloc(pieces[index]);
+                assert_eq!(tree.validate(), Ok(()));
+            }
+            let chunks: Vec<_> = tree.free_chunks().collect();
+            assert_eq!(chunks, [(start.cast(), 16 * PAGE_SIZE)]);
+            assert_eq!(tree.stats().mmap_calls, 1);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(feature = "guard-pages", ignore = "guarded chunks are never split")]
+    fn unmapping_a_piece_fixes_its_neighbours() {
+        let mut tree = AVLTree::new();
+        unsafe {
+            let whole = tree.alloc(pages(16));
+            tree.dealloc(whole);
+            let pieces: Vec<_> = (0..5).map(|_| tree.alloc(pages(3))).collect();
+            let nodes: Vec<_> = pieces.iter().map(|&piece| Node::from_data(piece)).collect();
+
+            // the middle piece and the page after the last one are free, trimming unmaps both
+            tree.dealloc(pieces[2]);
+            tree.trim();
+            assert_eq!(tree.validate(), Ok(()));
+            assert_eq!(tree.stats().munmap_calls, 2);
+
+            assert!(!nodes[0].as_ref().header.last);
+            assert!(nodes[1].as_ref().header.last);
+            assert_eq!(nodes[3].as_ref().header.prev_size, 0);
+            assert_eq!(nodes[4].as_ref().header.prev_size, 3 * PAGE_SIZE);
+            assert!(nodes[4].as_ref().header.last);
+
+            // each side merges on its own, nothing reaches across the hole
+            tree.dealloc(pieces[0]);
+            tree.dealloc(pieces[1]);
+            tree.dealloc(pieces[4]);
+            tree.dealloc(pieces[3]);
+            assert_eq!(tree.validate(), Ok(()));
+            let chunks: Vec<_> = tree.free_chunks().collect();
+            assert_eq!(chunks, [(nodes[0].cast(), 6 * PAGE_SIZE), (nodes[3].cast(), 6 * PAGE_SIZE)]);
+
+            tree.trim();
+            assert_eq!(tree.stats().mapped_bytes, 0);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(feature = "guard-pages", ignore = "guarded chunks always move")]
+    fn whole_mappings_are_resized_with_mremap() {
+        let mut tree = AVLTree::new();
+        unsafe {
+            let layout = pages(10);
+            let ptr = tree.alloc(layout);
+            for offset in 0..layout.size() {
+                ptr.add(offset).write(offset as u8);
+            }
+
+            let grown = tree.realloc(ptr, layout, 100 * PAGE_SIZE);
+            let stats = tree.stats();
+            assert_eq!((stats.mmap_calls, stats.mremap_calls), (1, 1));
+            assert_eq!(stats.mapped_bytes, 101 * PAGE_SIZE);
+            for offset in 0..layout.size() {
+                assert_eq!(*grown.add(offset), offset as u8, "byte {offset} changed");
+            }
+
+            // shrinking never has to move
+            let shrunk = tree.realloc(grown, Layout::from_size_align(100 * PAGE_SIZE, 8).unwrap(), layout.size());
+            assert_eq!(shrunk, grown);
+            let stats = tree.stats();
+            assert_eq!((stats.mmap_calls, stats.mremap_calls), (1, 2));
+            assert_eq!(stats.mapped_bytes, 10 * PAGE_SIZE);
+            assert_eq!(tree.cached_bytes(), 0);
+
+            tree.dealloc(shrunk);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(feature = "guard-pages", ignore = "guarded chunks always move")]
+    fn over_aligned_mappings_are_resized_in_place() {
+        let mut tree = AVLTree::new();
+        let align = 16 * PAGE_SIZE;
+        unsafe {
+            let layout = Layout::from_size_align(20 * PAGE_SIZE, align).unwrap();
+            let ptr = tree.alloc(layout);
+            ptr.write_bytes(7, layout.size());
+
+            let shrunk = tree.realloc(ptr, layout, 4 * PAGE_SIZE);
+            assert_eq!(shrunk, ptr);
+            assert_eq!(tree.stats().mremap_calls, 1);
+
+            // the kernel mustn't move the mapping, so with the page after it taken the data is
+            // copied to a fresh, aligned chunk instead
+            let node = Node::from_data(shrunk);
+            let end = node.cast::<u8>().as_ptr().add(node.as_ref().header.size);
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_FIXED_NOREPLACE;
+            let blocker = libc::mmap(end.cast(), PAGE_SIZE, libc::PROT_NONE, flags, -1, 0);
+            assert_eq!(blocker, end.cast());
+
+            let small = Layout::from_size_align(4 * PAGE_SIZE, align).unwrap();
+            let grown = tree.realloc(shrunk, small, 40 * PAGE_SIZE);
+            assert_ne!(grown, shrunk);
+            assert_eq!(grown as usize % align, 0);
+            let stats = tree.stats();
+            assert_eq!((stats.mmap_calls, stats.mremap_calls), (2, 1));
+            assert!((0..small.size()).all(|offset| *grown.add(offset) == 7));
+
+            libc::munmap(blocker, PAGE_SIZE);
+            tree.dealloc(grown);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(feature = "guard-pages", ignore = "guarded chunks always move")]
+    fn shrinking_returns_the_tail_to_the_tree() {
+        let mut tree = AVLTree::new();
+        unsafe {
+            // a chunk cut from a bigger one, so it can't be resized with mremap
+            let whole = tree.alloc(pages(32));
+            tree.dealloc(whole);
+            let ptr = tree.alloc(pages(20));
+            let node = Node::from_data(ptr);
+            assert!(!node.as_ref().is_whole_mapping());
+            assert_eq!(tree.cached_bytes(), 12 * PAGE_SIZE);
+            ptr.write_bytes(9, pages(5).size());
+
+            let shrunk = tree.realloc(ptr, pages(20), pages(5).size());
+            assert_eq!(shrunk, ptr);
+            assert!((0..pages(5).size()).all(|offset| *shrunk.add(offset) == 9));
+            assert_eq!(node.as_ref().header.size, 5 * PAGE_SIZE);
+
+            // the 15 pages cut off merge with the 12 already free after them
+            let tail = node.cast::<u8>().add(5 * PAGE_SIZE);
+            assert_eq!(tree.cached_bytes(), 27 * PAGE_SIZE);
+            assert_eq!(tree.free_chunks().collect::<Vec<_>>(), [(tail, 27 * PAGE_SIZE)]);
+
+            let reused = tree.alloc(pages(10));
+            assert_eq!(Node::from_data(reused).cast(), tail);
+            let stats = tree.stats();
+            assert_eq!((stats.mmap_calls, stats.mremap_calls), (1, 0));
+            assert_eq!(tree.cached_bytes(), 17 * PAGE_SIZE);
+
+            tree.dealloc(reused);
+            tree.dealloc(shrunk);
+            assert_eq!(tree.validate(), Ok(()));
+            assert_eq!(tree.free_chunks().collect::<Vec<_>>(), [(node.cast(), 32 * PAGE_SIZE)]);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ffi::c_void;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::Relaxed;
+
+use libc::{c_int, EINVAL, ENOMEM};
+
+use crate::common::PAGE_SIZE;
+use crate::{AVLTree, Allocator};
+
+// The malloc family for C programs, built into liballoc_expr.so by the crate in preload/:
+//
+//     cargo build --release --manifest-path preload/Cargo.toml
+//     LD_PRELOAD=preload/target/release/liballoc_expr.so ls
+//
+// This crate can't be the cdylib itself, a cdylib has to link a panic handler and that would
+// break no_std builds. The preload crate has a workspace of its own, so c-abi is never turned on
+// for this crate's tests just because both were built together.
+//
+// `free` and `realloc` aren't told the size or alignment of what they're given, so every
+// allocation carries a prefix recording the size and alignment it was made with, right in front
+// of the pointer handed out. That's also what `malloc_usable_size` reports.
+//
+// A multithreaded program can fork while another thread is inside the allocator. Only the forking
+// thread carries on in the child, so a lock held by any other one would stay locked forever. Fork
+// handlers take every lock before the fork and release them again on both sides, the same as
+// glibc does for its own malloc.
+
+static ALLOCATOR: Allocator<AVLTree> = Allocator::new();
+
+/// Set once the first allocation has registered the fork handlers.
+static FORK_HANDLERS: AtomicBool = AtomicBool::new(false);
+
+/// Bytes in front of each allocation holding its size and alignment. malloc has to return
+/// memory aligned for any type, which is 16 bytes on x86-64 and aarch64.
+const PREFIX_SIZE: usize = 16;
+
+/// Alignment `malloc`, `calloc` and `realloc` give their memory.
+const MIN_ALIGN: usize = 16;
+
+#[repr(C)]
+struct Prefix {
+    size: usize,
+    align: usize,
+}
+
+/// The layout asked of the allocator to serve `size` bytes aligned to `align`, with room for the
+/// prefix in front. Padding the prefix out to the alignment keeps the returned pointer aligned.
+fn outer_layout(size: usize, align: usize) -> Option<Layout> {
+    let align = align.max(MIN_ALIGN);
+    let padding = align.max(PREFIX_SIZE);
+    Layout::from_size_align(size.checked_add(padding)?, align).ok()
+}
+
+unsafe fn allocate(size: usize, align: usize) -> *mut u8 {
+    // registering may allocate, which comes straight back here with the flag already set
+    if !FORK_HANDLERS.load(Relaxed) && !FORK_HANDLERS.swap(true, Relaxed) {
+        libc::pthread_atfork(Some(before_fork), Some(after_fork), Some(after_fork));
+    }
+
+    let Some(layout) = outer_layout(size, align) else {
+        return null_mut();
+    };
+    let outer = ALLOCATOR.alloc(layout);
+    if outer.is_null() {
+        return null_mut();
+    }
+
+    let ptr = outer.add(layout.size() - size);
+    ptr.cast::<Prefix>().sub(1).write(Prefix { size, align });
+    ptr
+}
+
+/// The prefix of `ptr`, where its allocation starts and the layout it was made with.
+unsafe fn inspect(ptr: *mut u8) -> (Prefix, *mut u8, Layout) {
+    let prefix = ptr.cast::<Prefix>().sub(1).read();
+    let layout = outer_layout(prefix.size, prefix.align).unwrap_unchecked();
+    let outer = ptr.sub(layout.size() - prefix.size);
+    (prefix, outer, layout)
+}
+
+unsafe extern "C" fn before_fork() {
+    ALLOCATOR.lock_all();
+}
+
+/// Runs in both the parent and the child.
+unsafe extern "C" fn after_fork() {
+    ALLOCATOR.unlock_all();
+}
+
+fn set_errno(errno: c_int) {
+    unsafe { *libc::__errno_location() = errno };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
+    let ptr = allocate(size, MIN_ALIGN);
+    if ptr.is_null() {
+        set_errno(ENOMEM);
+    }
+    ptr.cast()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+    let (_, outer, layout) = inspect(ptr.cast());
+    ALLOCATOR.dealloc(outer, layout);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn calloc(count: usize, size: usize) -> *mut c_void {
+    let Some(size) = count.checked_mul(size) else {
+        set_errno(ENOMEM);
+        return null_mut();
+    };
+    let ptr = malloc(size);
+    if !ptr.is_null() {
+        ptr.write_bytes(0, size);
+    }
+    ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+    if ptr.is_null() {
+        return malloc(size);
+    }
+    // glibc frees and returns null for a size of zero
+    if size == 0 {
+        free(ptr);
+        return null_mut();
+    }
+
+    let (prefix, outer, layout) = inspect(ptr.cast());
+    let Some(new_layout) = outer_layout(size, prefix.align) else {
+        set_errno(ENOMEM);
+        return null_mut();
+    };
+    let new_outer = ALLOCATOR.realloc(outer, layout, new_layout.size());
+    if new_outer.is_null() {
+        set_errno(ENOMEM);
+        return null_mut();
+    }
+
+    // the padding in front only depends on the alignment, so the data is still right after it
+    let ptr = new_outer.add(new_layout.size() - size);
+    ptr.cast::<Prefix>().sub(1).write(Prefix { size, align: prefix.align });
+    ptr.cast()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(out: *mut *mut c_void, align: usize, size: usize) -> c_int {
+    if !align.is_power_of_two() || !align.is_multiple_of(size_of::<*mut c_void>()) {
+        return EINVAL;
+    }
+    let ptr = allocate(size, align);
+    if ptr.is_null() {
+        return ENOMEM;
+    }
+    *out = ptr.cast();
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aligned_alloc(align: usize, size: usize) -> *mut c_void {
+    memalign(align, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn memalign(align: usize, size: usize) -> *mut c_void {
+    if !align.is_power_of_two() {
+        set_errno(EINVAL);
+        return null_mut();
+    }
+    let ptr = allocate(size, align);
+    if ptr.is_null() {
+        set_errno(ENOMEM);
+    }
+    ptr.cast()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn valloc(size: usize) -> *mut c_void {
+    memalign(PAGE_SIZE, size)
+}
+
+/// Like `valloc`, with the size rounded up to whole pages.
+#[no_mangle]
+pub unsafe extern "C" fn pvalloc(size: usize) -> *mut c_void {
+    let Some(size) = size.max(1).checked_next_multiple_of(PAGE_SIZE) else {
+        set_errno(ENOMEM);
+        return null_mut();
+    };
+    memalign(PAGE_SIZE, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> usize {
+    if ptr.is_null() {
+        return 0;
+    }
+    ptr.cast::<Prefix>().sub(1).read().size
+}
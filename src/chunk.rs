@@ -0,0 +1,147 @@
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ptr::NonNull;
+
+use crate::common::{protect_memory, release_memory, request_memory, PAGE_SIZE};
+use crate::stats::MapCounters;
+
+// Layout of a large allocation, shared by the tree allocators. A chunk is a page aligned run of
+// pages that starts with the tree's node. The node stays in place while the chunk is handed out,
+// and the data goes after it at the first suitably aligned address, with the distance from the
+// data back to the chunk's start stored in the word right in front of the data.
+//
+// A guarded chunk is a mapping of its own, with an inaccessible page on either side of it. Its
+// data is placed at the end instead, so it runs right up to the guard page after it.
+
+/// Bytes in front of the data of a chunk whose node is `node_size` bytes, before alignment.
+const fn header_size(node_size: usize) -> usize {
+    node_size.next_multiple_of(size_of::<usize>()) + size_of::<usize>()
+}
+
+/// Where the data for `layout` sits. The first value is its distance from the start of a page
+/// aligned chunk. Alignments beyond a page can't be guaranteed by the chunk's own start, so the
+/// second value is how much further along the data may have to move to reach an aligned address.
+fn placement(node_size: usize, layout: Layout) -> (usize, usize) {
+    let lead = header_size(node_size).next_multiple_of(layout.align().min(PAGE_SIZE));
+    (lead, layout.align().saturating_sub(PAGE_SIZE))
+}
+
+/// Length of a chunk that can hold `layout` wherever the chunk starts, None on overflow.
+pub fn chunk_size(node_size: usize, layout: Layout) -> Option<usize> {
+    let (lead, slack) = placement(node_size, layout);
+    lead.checked_add(slack)?.checked_add(layout.size())?.checked_next_multiple_of(PAGE_SIZE)
+}
+
+/// Maps a new chunk big enough for `layout`, returning where it starts and its length. None if
+/// the size overflows or the OS refuses.
+///
+/// For alignments past a page the mapping is made big enough to contain an aligned spot, and the
+/// pages either side of the chunk placed there are unmapped again.
+pub unsafe fn map(
+    node_size: usize,
+    layout: Layout,
+    counters: &mut MapCounters,
+) -> Option<(NonNull<u8>, usize)> {
+    let (lead, slack) = placement(node_size, layout);
+    let size = lead.checked_add(layout.size())?.checked_next_multiple_of(PAGE_SIZE)?;
+    let mapped = size.checked_add(slack)?;
+
+    let address = request_memory(mapped).ok()?;
+    counters.mapped(mapped);
+
+    let start = (address.as_ptr() as usize + lead).next_multiple_of(layout.align()) - lead;
+    let head = start - address.as_ptr() as usize;
+    Some((trim(address, mapped, head, size, counters), size))
+}
+
+/// Unmaps all of a `mapped` bytes long mapping but the `length` bytes starting `head` bytes in,
+/// returning where those start.
+unsafe fn trim(
+    address: NonNull<u8>,
+    mapped: usize,
+    head: usize,
+    length: usize,
+    counters: &mut MapCounters,
+) -> NonNull<u8> {
+    let tail = mapped - head - length;
+    if head > 0 {
+        release_memory(address, head);
+        counters.unmapped(head);
+    }
+    if tail > 0 {
+        release_memory(NonNull::new_unchecked(address.as_ptr().add(head + length)), tail);
+        counters.unmapped(tail);
+    }
+    NonNull::new_unchecked(address.as_ptr().add(head))
+}
+
+/// Length of a guarded chunk that can hold `layout`, and how far before the chunk's end its
+/// data starts. None on overflow.
+fn guarded_placement(node_size: usize, layout: Layout) -> Option<(usize, usize)> {
+    let tail = layout.size().checked_next_multiple_of(layout.align())?;
+    let size = tail.checked_add(header_size(node_size))?.checked_next_multiple_of(PAGE_SIZE)?;
+    Some((size, tail))
+}
+
+/// Maps a guarded chunk big enough for `layout`, returning where it starts and its length. The
+/// guard pages aren't counted in the length. None if the size overflows or the OS refuses.
+pub unsafe fn map_guarded(
+    node_size: usize,
+    layout: Layout,
+    counters: &mut MapCounters,
+) -> Option<(NonNull<u8>, usize)> {
+    let (size, _) = guarded_placement(node_size, layout)?;
+    let guarded = size.checked_add(2 * PAGE_SIZE)?;
+    let mapped = guarded.checked_add(layout.align().saturating_sub(PAGE_SIZE))?;
+
+    let address = request_memory(mapped).ok()?;
+    counters.mapped(mapped);
+
+    // the data ends where the guard page after the chunk starts, so that has to be aligned for it
+    let end = (address.as_ptr() as usize + PAGE_SIZE + size).next_multiple_of(layout.align().max(PAGE_SIZE));
+    let head = end - size - PAGE_SIZE - address.as_ptr() as usize;
+    let guard = trim(address, mapped, head, guarded, counters);
+
+    let chunk = NonNull::new_unchecked(guard.as_ptr().add(PAGE_SIZE));
+    let after = NonNull::new_unchecked(chunk.as_ptr().add(size));
+    if protect_memory(guard, PAGE_SIZE).is_err() || protect_memory(after, PAGE_SIZE).is_err() {
+        unmap_guarded(chunk, size, counters);
+        return None;
+    }
+    Some((chunk, size))
+}
+
+/// Unmaps a chunk from `map_guarded` along with its guard pages.
+pub unsafe fn unmap_guarded(chunk: NonNull<u8>, size: usize, counters: &mut MapCounters) {
+    release_memory(NonNull::new_unchecked(chunk.as_ptr().sub(PAGE_SIZE)), size + 2 * PAGE_SIZE);
+    counters.unmapped(size + 2 * PAGE_SIZE);
+}
+
+/// Lays the data for `layout` out in the chunk starting at `chunk`, and records the way back to
+/// it. The chunk has to be at least `chunk_size(node_size, layout)` long.
+pub unsafe fn place_data(chunk: NonNull<u8>, node_size: usize, layout: Layout) -> *mut u8 {
+    let start = chunk.as_ptr() as usize;
+    let offset = (start + header_size(node_size)).next_multiple_of(layout.align()) - start;
+
+    let data = chunk.as_ptr().add(offset);
+    data.cast::<usize>().sub(1).write(offset);
+    data
+}
+
+/// Lays the data for `layout` out at the end of the guarded chunk starting at `chunk`, and
+/// records the way back to it. If the size isn't a multiple of the alignment the data stops
+/// short of the end by the difference.
+pub unsafe fn place_data_at_end(chunk: NonNull<u8>, node_size: usize, layout: Layout) -> *mut u8 {
+    let (size, tail) = guarded_placement(node_size, layout).unwrap_unchecked();
+    let offset = size - tail;
+
+    let data = chunk.as_ptr().add(offset);
+    data.cast::<usize>().sub(1).write(offset);
+    data
+}
+
+/// Start of the chunk `ptr` was placed in by `place_data` or `place_data_at_end`.
+pub unsafe fn from_data(ptr: *mut u8) -> NonNull<u8> {
+    let offset = ptr.cast::<usize>().sub(1).read();
+    NonNull::new_unchecked(ptr.sub(offset))
+}
@@ -1,17 +1,68 @@
-use std::ptr::NonNull;
+use core::ptr::NonNull;
 
-use libc::{MAP_ANON, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+use libc::{MAP_ANON, MAP_PRIVATE, MREMAP_MAYMOVE, PROT_NONE, PROT_READ, PROT_WRITE};
 
 pub const PAGE_SIZE: usize = 4096;
 
-pub unsafe fn request_memory(length: usize) -> NonNull<u8> {
+/// Maps `length` bytes of fresh zeroed pages, on failure the errno mmap left behind is returned
+/// so the caller can decide what to do about it. Inside an allocator that's usually handing back
+/// null, panicking would take the whole process down.
+///
+/// # Safety
+///
+/// `length` must not be zero.
+pub unsafe fn request_memory(length: usize) -> Result<NonNull<u8>, i32> {
     let protections = PROT_READ | PROT_WRITE;
     let flags = MAP_ANON | MAP_PRIVATE;
 
     match libc::mmap(core::ptr::null_mut(), length, protections, flags, -1, 0) {
-        // todo: I should probably use AllocationError on nightly here
-        libc::MAP_FAILED => panic!("Failed to request memory!"),
-        address => NonNull::new_unchecked(address).cast()
+        libc::MAP_FAILED => Err(*libc::__errno_location()),
+        address => Ok(NonNull::new_unchecked(address).cast())
     }
+}
+
+/// Gives memory from `request_memory` back to the OS. `length` is rounded up to whole pages, and
+/// any page aligned part of a mapping can be released on its own.
+///
+/// # Safety
+///
+/// `address` must be page aligned and the range mapped, and nothing in it may be used afterwards.
+pub unsafe fn release_memory(address: NonNull<u8>, length: usize) {
+    let result = libc::munmap(address.as_ptr().cast(), length);
+    debug_assert_eq!(result, 0, "Failed to release memory!");
+}
+
+/// Makes pages from `request_memory` inaccessible, so touching them faults. On failure the errno
+/// is returned and the pages are left as they were.
+///
+/// # Safety
+///
+/// `address` must be page aligned and the range mapped, and nothing in it may be used afterwards.
+pub unsafe fn protect_memory(address: NonNull<u8>, length: usize) -> Result<(), i32> {
+    match libc::mprotect(address.as_ptr().cast(), length, PROT_NONE) {
+        0 => Ok(()),
+        _ => Err(*libc::__errno_location()),
+    }
+}
 
+/// Resizes a whole mapping from `request_memory` with mremap, which moves page tables instead of
+/// copying. If it can't grow where it is and `may_move` is set the kernel moves it, so the
+/// returned address may differ. On failure the errno is returned and the mapping is untouched.
+///
+/// # Safety
+///
+/// `address` and `old_length` must cover a whole mapping. Once it succeeds the old address may
+/// only be used if it's the one returned.
+pub unsafe fn resize_memory(
+    address: NonNull<u8>,
+    old_length: usize,
+    new_length: usize,
+    may_move: bool,
+) -> Result<NonNull<u8>, i32> {
+    let flags = if may_move { MREMAP_MAYMOVE } else { 0 };
+
+    match libc::mremap(address.as_ptr().cast(), old_length, new_length, flags) {
+        libc::MAP_FAILED => Err(*libc::__errno_location()),
+        address => Ok(NonNull::new_unchecked(address).cast())
+    }
 }
@@ -1,7 +1,48 @@
-use std::alloc::Layout;
+use core::alloc::Layout;
 
+use crate::stats::Stats;
+
+/// Backing allocator for everything too big for the segregated lists.
+///
+/// `alloc` and `realloc` return null when the memory can't be had, leaving the caller to report
+/// it, through `handle_alloc_error` or a failed `try_reserve` for instance. On a failed `realloc`
+/// the original allocation is untouched.
+///
+/// # Safety
+///
+/// `alloc` and `realloc` must return memory that is valid for the requested layout and not handed
+/// out again until it's passed back to `dealloc`.
 pub unsafe trait LargeAllocator {
+    /// # Safety
+    ///
+    /// `layout` must have a non-zero size.
     unsafe fn alloc(&mut self, layout: Layout) -> *mut u8;
+
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc` or `realloc` on this allocator and not freed since.
     unsafe fn dealloc(&mut self, ptr: *mut u8);
+
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation from this allocator made with `layout`, and `new_size` must
+    /// be non-zero. On success `ptr` must no longer be used.
     unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8;
-}
\ No newline at end of file
+
+    /// Bytes that can be used at `ptr`, at least `layout.size()`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation from this allocator made with `layout`.
+    unsafe fn usable_size(&self, _ptr: *mut u8, layout: Layout) -> usize {
+        layout.size()
+    }
+
+    /// Gives every free chunk the allocator is holding on to back to the OS.
+    fn trim(&mut self) {}
+
+    /// What the allocator holds right now. All zero for an allocator that doesn't keep count.
+    fn stats(&self) -> Stats {
+        Stats::default()
+    }
+}
@@ -1,33 +1,288 @@
-use std::alloc::{GlobalAlloc, Layout};
-use linked_list::LinkedList;
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
-use crate::large_allocator::LargeAllocator;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp::min;
+use core::ptr::{copy_nonoverlapping, null_mut, NonNull};
+use core::sync::atomic::Ordering::Relaxed;
+use common::PAGE_SIZE;
+use linked_list::{record_pages, REFILL_SIZE};
+use lock::Mutex;
+use stats::{ClassCounters, LargeCounters};
+use thread_cache::{ThreadCache, BATCH_SIZE, CACHE_CAPACITY, MAX_THREADS};
+
+#[cfg(feature = "allocator-api2")]
+pub use crate::allocator_api::Locked;
+pub use crate::avl_tree::{AVLTree, FreeChunks, DEFAULT_MAX_CACHED_BYTES};
+pub use crate::large_allocator::LargeAllocator;
+pub use crate::linked_list::LinkedList;
+pub use crate::rb_tree::{Chunk, Iter, RBTree};
+pub use crate::size_class::{SizeClasses, DEFAULT_SIZE_CLASSES};
+pub use crate::stats::{AllocatorStats, ClassStats, Stats};
 
 mod avl_tree;
+#[cfg(feature = "allocator-api2")]
+mod allocator_api;
+#[cfg(feature = "c-abi")]
+mod c_abi;
+mod chunk;
 mod linked_list;
 mod large_allocator;
+mod lock;
+mod poison;
 mod rb_tree;
-mod common;
+pub mod common;
+mod size_class;
+mod stats;
+mod thread_cache;
 
-struct Allocator<T: LargeAllocator> {
-    segregated_list: [LinkedList; 7],
-    mmapped_values: T
+/// Allocations up to the largest size class are served from the segregated lists, everything
+/// else goes to the large allocator `T`. Each list and the large allocator sit behind their own
+/// lock, so threads only contend when they hit the same one.
+///
+/// `N` is the number of size classes, their sizes come from the table the allocator is built
+/// with.
+///
+/// Small objects are cached per thread in front of the segregated lists, so most small
+/// allocations and frees touch neither a lock nor an atomic. A cache moves objects to and from its
+/// list in batches when it runs dry or grows past `CACHE_CAPACITY`. The caches need thread locals,
+/// so without the `std` feature every thread goes to the shared lists.
+///
+/// The caches for all `MAX_THREADS` thread indices are part of the allocator itself, 192 bytes
+/// each with the default classes, so an `Allocator` is almost 25 KiB. One made for a single
+/// collection is better boxed or kept in a `static` than built on the stack.
+///
+/// Dropping an allocator unmaps every page its lists were refilled from, so no small object may
+/// outlive it, and drops the large allocator, which unmaps the chunks it has cached.
+///
+/// With the `poison` feature on in a debug build, a freed object is filled with a pattern that's
+/// checked before the object is handed out again, so a write after free aborts with a report of
+/// where it landed. `AVLTree` does the same for its chunks.
+///
+/// The constructors are `const`, so an allocator can be a `static` and serve as the
+/// `#[global_allocator]`:
+///
+/// ```
+/// use alloc_expr::{Allocator, AVLTree};
+///
+/// #[global_allocator]
+/// static GLOBAL: Allocator<AVLTree> = Allocator::new();
+///
+/// let v: Vec<u64> = (0..10_000).collect();
+/// assert_eq!(v.iter().sum::<u64>(), 49_995_000);
+/// ```
+pub struct Allocator<T: LargeAllocator, const N: usize = 7> {
+    classes: SizeClasses<N>,
+    thread_caches: [ThreadCache<N>; MAX_THREADS],
+    segregated_list: [Mutex<LinkedList>; N],
+    /// each shared list's counts, readable without its lock
+    class_counters: [ClassCounters; N],
+    /// created by the first large allocation, `T::default` can't run in a const context
+    mmapped_values: Mutex<Option<T>>,
+    /// the large allocator's stats, readable without its lock
+    large_counters: LargeCounters,
 }
 
-impl<T: LargeAllocator> Allocator<T> {
+impl<T: LargeAllocator + Default> Allocator<T> {
+    /// An allocator using `DEFAULT_SIZE_CLASSES`.
+    pub const fn new() -> Self {
+        Self::with_size_classes(DEFAULT_SIZE_CLASSES)
+    }
+}
+
+impl<T: LargeAllocator + Default, const N: usize> Allocator<T, N> {
+    /// An allocator with a segregated list for each class in `classes`.
+    pub const fn with_size_classes(classes: SizeClasses<N>) -> Self {
+        Allocator {
+            classes,
+            thread_caches: [const { ThreadCache::new() }; MAX_THREADS],
+            segregated_list: [const { Mutex::new(LinkedList::new()) }; N],
+            class_counters: [const { ClassCounters::new() }; N],
+            mmapped_values: Mutex::new(None),
+            large_counters: LargeCounters::new(),
+        }
+    }
 
+    /// Runs `f` on the large allocator with its lock held, creating it on first use.
+    fn with_large<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut large = self.mmapped_values.lock();
+        let large = large.get_or_insert_with(T::default);
+        let result = f(large);
+        self.large_counters.store(large.stats());
+        result
+    }
+}
+
+impl<T: LargeAllocator + Default> Default for Allocator<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-unsafe impl<T: LargeAllocator> GlobalAlloc for Allocator<T> {
+impl<T: LargeAllocator, const N: usize> Allocator<T, N> {
+    /// Returns the large allocator's free chunks to the OS. Small objects are kept, their pages
+    /// are shared with objects that are still in use.
+    pub fn trim(&self) {
+        if let Some(large) = self.mmapped_values.lock().as_mut() {
+            large.trim();
+            self.large_counters.store(large.stats());
+        }
+    }
+
+    /// A snapshot of what the allocator holds. Everything is read from atomics without taking a
+    /// lock or stopping other threads, so it's cheap enough to scrape often, but under load the
+    /// counts can be a few objects or chunks apart.
+    pub fn stats(&self) -> AllocatorStats<N> {
+        let mut small = Stats::default();
+        let classes = core::array::from_fn(|class| {
+            let size = self.classes.size(class);
+            let counters = &self.class_counters[class];
+            let refills = counters.refills.load(Relaxed);
+            let cached: usize = self.thread_caches.iter().map(|cache| cache.cached(class)).sum();
+
+            let free = counters.free.load(Relaxed) + cached;
+            let allocated = (refills * (REFILL_SIZE / size)).saturating_sub(free);
+
+            let records = record_pages(refills);
+            small.mapped_bytes += refills * REFILL_SIZE + records * PAGE_SIZE;
+            small.allocated_bytes += allocated * size;
+            small.free_bytes += free * size;
+            small.mmap_calls += refills + records;
+            ClassStats { size, allocated, free }
+        });
+        let large = self.large_counters.load();
+
+        AllocatorStats { small, classes, large }
+    }
+
+    /// Runs `f` on the large allocator with its lock held, to walk its free chunks for instance.
+    /// None if nothing large has been allocated yet. `f` mustn't make large allocations through
+    /// this allocator, the lock would never be released.
+    pub fn inspect_large<R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.mmapped_values.lock().as_ref().map(f)
+    }
+
+    /// Takes every lock in the allocator and keeps them, so that a fork can't copy a lock another
+    /// thread holds into the child, where nothing would ever release it.
+    #[cfg(feature = "c-abi")]
+    fn lock_all(&self) {
+        for list in &self.segregated_list {
+            core::mem::forget(list.lock());
+        }
+        core::mem::forget(self.mmapped_values.lock());
+    }
+
+    /// Releases the locks taken by `lock_all`.
+    ///
+    /// # Safety
+    ///
+    /// Only after `lock_all`, in the parent or the child once the fork is done.
+    #[cfg(feature = "c-abi")]
+    unsafe fn unlock_all(&self) {
+        self.mmapped_values.force_unlock();
+        for list in &self.segregated_list {
+            list.force_unlock();
+        }
+    }
+
+    /// Copies a shared list's counts out to its atomics, with the list's lock held.
+    fn update_counters(&self, class: usize, list: &LinkedList) {
+        let counters = &self.class_counters[class];
+        counters.refills.store(list.refills(), Relaxed);
+        counters.free.store(list.len(), Relaxed);
+    }
+
+    unsafe fn alloc_small(&self, class: usize) -> *mut u8 {
+        let size = self.classes.size(class);
+        let ptr = match thread_cache::current_index() {
+            None => {
+                let mut list = self.segregated_list[class].lock();
+                let ptr = list.alloc(size);
+                self.update_counters(class, &list);
+                ptr
+            }
+            Some(index) => {
+                let cache = &self.thread_caches[index];
+                let bin = &mut cache.bins()[class];
+                if bin.is_empty() {
+                    let mut list = self.segregated_list[class].lock();
+                    for _ in 0..BATCH_SIZE {
+                        match NonNull::new(list.alloc(size)) {
+                            Some(object) => bin.push(object),
+                            None => break,
+                        }
+                    }
+                    self.update_counters(class, &list);
+                }
+                let ptr = bin.pop().map_or(null_mut(), NonNull::as_ptr);
+                cache.update_cached(class, bin.len());
+                ptr
+            }
+        };
+
+        // the first word held the list's link while the object was free
+        if poison::ENABLED && !ptr.is_null() {
+            poison::check(ptr, size_of::<usize>(), size);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc_small(&self, ptr: *mut u8, class: usize) {
+        if poison::ENABLED {
+            poison::fill(ptr, size_of::<usize>(), self.classes.size(class));
+        }
+
+        let Some(index) = thread_cache::current_index() else {
+            let mut list = self.segregated_list[class].lock();
+            list.dealloc(ptr);
+            return self.update_counters(class, &list);
+        };
+
+        let cache = &self.thread_caches[index];
+        let bin = &mut cache.bins()[class];
+        bin.push(NonNull::new_unchecked(ptr));
+        if bin.len() > CACHE_CAPACITY {
+            let mut list = self.segregated_list[class].lock();
+            for object in core::iter::from_fn(|| bin.pop()).take(BATCH_SIZE) {
+                list.push(object);
+            }
+            self.update_counters(class, &list);
+        }
+        cache.update_cached(class, bin.len());
+    }
+}
+
+unsafe impl<T: LargeAllocator + Default, const N: usize> GlobalAlloc for Allocator<T, N> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        todo!()
+        match self.classes.class_of(layout) {
+            Some(class) => self.alloc_small(class),
+            None => self.with_large(|large| large.alloc(layout)),
+        }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        todo!()
+        match self.classes.class_of(layout) {
+            Some(class) => self.dealloc_small(ptr, class),
+            None => self.with_large(|large| large.dealloc(ptr)),
+        }
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        todo!()
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        match (self.classes.class_of(layout), self.classes.class_of(new_layout)) {
+            // the object already has room for the new size
+            (Some(old), Some(new)) if old == new => ptr,
+            // both sides live in the large allocator, let it decide whether to move
+            (None, None) => self.with_large(|large| large.realloc(ptr, layout, new_size)),
+            // moving between size classes, or across the small/large threshold
+            _ => {
+                let new_ptr = self.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            }
+        }
     }
 }
@@ -1,3 +1,205 @@
+use core::mem::size_of;
+use core::ptr::NonNull;
+
+use crate::common::{release_memory, request_memory, PAGE_SIZE};
+use crate::poison;
+
+/// How much memory to carve into objects each time a list runs dry.
+pub(crate) const REFILL_SIZE: usize = 4 * PAGE_SIZE;
+
+/// Refill addresses kept in each page of a list's record.
+const RECORD_LEN: usize = PAGE_SIZE / size_of::<usize>() - 1;
+
+/// A free object, the link to the next one lives in the object's own memory.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Pages a list maps to record where `refills` refills are, each one a mapping of its own.
+pub(crate) fn record_pages(refills: usize) -> usize {
+    refills.div_ceil(RECORD_LEN)
+}
+
+/// A page of the addresses `refill` has mapped, linked to the page filled before it.
+struct RecordPage {
+    previous: Option<NonNull<RecordPage>>,
+    refills: [*mut u8; RECORD_LEN],
+}
+
+/// The objects of a list, with nothing else. A thread cache's bins are these, they only ever hold
+/// objects that came from a shared list.
+#[derive(Default)]
+pub struct FreeList {
+    head: Option<NonNull<FreeObject>>,
+    len: usize,
+}
+
+// the list owns every object linked into it, nothing else points at them while they're free
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    pub const fn new() -> Self {
+        FreeList { head: None, len: 0 }
+    }
+
+    /// Number of free objects currently in the list.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    /// Links a free object into the front of the list.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to memory of the list's object size, aligned for a pointer, that nothing
+    /// else uses until it's popped again.
+    pub unsafe fn push(&mut self, ptr: NonNull<u8>) {
+        let mut object: NonNull<FreeObject> = ptr.cast();
+        object.as_mut().next = self.head;
+        self.head = Some(object);
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<NonNull<u8>> {
+        let object = self.head?;
+        self.head = unsafe { object.as_ref().next };
+        self.len -= 1;
+        Some(object.cast())
+    }
+}
+
+/// Intrusive singly linked list of free objects of a single size class.
+///
+/// The list owns the pages it refills from. Dropping it unmaps all of them, along with every
+/// object carved out of them, whether it's still in the list, handed out, or pushed onto another
+/// list since.
+#[derive(Default)]
 pub struct LinkedList {
+    free: FreeList,
+    /// times the list has mapped `REFILL_SIZE` bytes of fresh pages
+    refills: usize,
+    /// where every refill starts, the latest page holds the most recent `RECORD_LEN` at most
+    record: Option<NonNull<RecordPage>>,
+}
+
+// the list owns every page it refilled from, and the record of them
+unsafe impl Send for LinkedList {}
+
+impl LinkedList {
+    pub const fn new() -> Self {
+        LinkedList { free: FreeList::new(), refills: 0, record: None }
+    }
+
+    /// Number of free objects currently in the list.
+    pub fn len(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Number of times the list has run dry and mapped fresh pages.
+    pub fn refills(&self) -> usize {
+        self.refills
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.free.is_empty()
+    }
+
+    /// Links a free object into the front of the list.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to memory of the list's object size, aligned for a pointer, that nothing
+    /// else uses until it's popped again.
+    pub unsafe fn push(&mut self, ptr: NonNull<u8>) {
+        self.free.push(ptr);
+    }
+
+    pub fn pop(&mut self) -> Option<NonNull<u8>> {
+        self.free.pop()
+    }
+
+    /// Hands out an object of `size` bytes, carving a fresh batch out of new pages if the list is
+    /// empty. Returns null if the list is empty and no more pages can be mapped.
+    ///
+    /// # Safety
+    ///
+    /// `size` has to be the same for every call on a list, at least pointer sized and a multiple
+    /// of a pointer's alignment.
+    pub unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+        if self.is_empty() {
+            self.refill(size);
+        }
+        self.pop().map_or(core::ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must have come from `alloc` on this list, or another list of the same object size.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        self.push(NonNull::new_unchecked(ptr));
+    }
+
+    /// Splits `REFILL_SIZE` bytes of fresh pages into objects of `size` bytes. They're pushed from
+    /// the back so they get handed out in address order. The list is left empty if the pages
+    /// can't be mapped.
+    unsafe fn refill(&mut self, size: usize) {
+        debug_assert!(size >= size_of::<FreeObject>() && size <= REFILL_SIZE);
+
+        let Ok(memory) = request_memory(REFILL_SIZE) else {
+            return;
+        };
+        if !self.record(memory.as_ptr()) {
+            release_memory(memory, REFILL_SIZE);
+            return;
+        }
+        let count = REFILL_SIZE / size;
+        // fresh objects are checked for poison like any other when they're handed out
+        if poison::ENABLED {
+            poison::fill(memory.as_ptr(), 0, REFILL_SIZE);
+        }
+
+        for index in (0..count).rev() {
+            self.push(NonNull::new_unchecked(memory.as_ptr().add(index * size)));
+        }
+    }
+
+    /// Adds a refill to the record and counts it, starting a new page of the record when the
+    /// last one is full. False if that page can't be mapped.
+    unsafe fn record(&mut self, memory: *mut u8) -> bool {
+        let slot = self.refills % RECORD_LEN;
+        if slot == 0 {
+            let Ok(page) = request_memory(PAGE_SIZE) else {
+                return false;
+            };
+            let mut page: NonNull<RecordPage> = page.cast();
+            page.as_mut().previous = self.record;
+            self.record = Some(page);
+        }
+        self.record.unwrap().as_mut().refills[slot] = memory;
+        self.refills += 1;
+        true
+    }
+}
 
+impl Drop for LinkedList {
+    fn drop(&mut self) {
+        let mut remaining = self.refills;
+        let mut record = self.record.take();
+        while let Some(page) = record {
+            // only the latest page can be partly filled
+            let filled = (remaining - 1) % RECORD_LEN + 1;
+            unsafe {
+                for &memory in &page.as_ref().refills[..filled] {
+                    release_memory(NonNull::new_unchecked(memory), REFILL_SIZE);
+                }
+                record = page.as_ref().previous;
+                release_memory(page.cast(), PAGE_SIZE);
+            }
+            remaining -= filled;
+        }
+    }
 }
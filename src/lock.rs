@@ -0,0 +1,128 @@
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+use libc::{FUTEX_PRIVATE_FLAG, FUTEX_WAIT, FUTEX_WAKE, SYS_futex};
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// locked, and at least one thread may be parked on the futex waiting for it
+const CONTENDED: u32 = 2;
+
+/// How many times to retry before parking, critical sections in the allocator are short so the
+/// lock is usually released well before a syscall would return.
+const SPIN_LIMIT: u32 = 100;
+
+/// A spin-then-park mutex sitting directly on the futex syscall. std's Mutex can't be used here as
+/// the allocator has to work without being able to allocate itself.
+pub struct Mutex<T> {
+    state: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+// the mutex hands out access to the value to one thread at a time
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            state: AtomicU32::new(UNLOCKED),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self.state.compare_exchange(UNLOCKED, LOCKED, Acquire, Relaxed).is_err() {
+            self.lock_contended();
+        }
+        MutexGuard { mutex: self }
+    }
+
+    #[cold]
+    fn lock_contended(&self) {
+        for _ in 0..SPIN_LIMIT {
+            if self.state.load(Relaxed) == UNLOCKED
+                && self.state.compare_exchange_weak(UNLOCKED, LOCKED, Acquire, Relaxed).is_ok()
+            {
+                return;
+            }
+            spin_loop();
+        }
+
+        // from here on the lock is marked as contended so the holder knows to wake someone up,
+        // this may cause one spurious wake up once the last waiter gets the lock
+        while self.state.swap(CONTENDED, Acquire) != UNLOCKED {
+            futex_wait(&self.state, CONTENDED);
+        }
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Release) == CONTENDED {
+            futex_wake(&self.state);
+        }
+    }
+
+    /// Releases a lock whose guard was forgotten, for holding a lock across calls the way fork
+    /// handlers have to.
+    ///
+    /// # Safety
+    ///
+    /// The lock has to be held, with no guard left to release it again.
+    #[cfg(feature = "c-abi")]
+    pub unsafe fn force_unlock(&self) {
+        self.unlock();
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// Sleeps while `futex` holds `expected`, returning straight away if it has already changed.
+fn futex_wait(futex: &AtomicU32, expected: u32) {
+    unsafe {
+        libc::syscall(
+            SYS_futex,
+            futex.as_ptr(),
+            FUTEX_WAIT | FUTEX_PRIVATE_FLAG,
+            expected,
+            core::ptr::null::<libc::timespec>(),
+        );
+    }
+}
+
+/// Wakes a single thread sleeping on `futex`.
+fn futex_wake(futex: &AtomicU32) {
+    unsafe {
+        libc::syscall(SYS_futex, futex.as_ptr(), FUTEX_WAKE | FUTEX_PRIVATE_FLAG, 1);
+    }
+}
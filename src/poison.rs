@@ -0,0 +1,68 @@
+use core::fmt::{self, Write};
+
+// Freed memory is filled with a pattern, and the pattern has to still be there when the memory
+// is handed out again. A write through a dangling pointer shows up at the next allocation that
+// reuses the memory, rather than as corruption somewhere else much later.
+
+/// Whether freed memory is poisoned and checked, see the `poison` feature.
+pub const ENABLED: bool = cfg!(all(debug_assertions, feature = "poison"));
+
+/// What every poisoned byte is set to.
+pub const PATTERN: u8 = 0x5a;
+
+/// Bytes shown from the row holding the first modified one.
+const DUMP_SIZE: usize = 64;
+
+const ROW_SIZE: usize = 16;
+
+/// Poisons bytes `start..end` of the memory at `chunk`.
+pub unsafe fn fill(chunk: *mut u8, start: usize, end: usize) {
+    chunk.add(start).write_bytes(PATTERN, end - start);
+}
+
+/// Checks that bytes `start..end` of the memory at `chunk` are still poisoned, aborting with a
+/// report if any were written to.
+pub unsafe fn check(chunk: *const u8, start: usize, end: usize) {
+    let bytes = core::slice::from_raw_parts(chunk.add(start), end - start);
+    if let Some(position) = bytes.iter().position(|&byte| byte != PATTERN) {
+        report(chunk, start + position, end);
+    }
+}
+
+/// Prints where the first modified byte is, with a hex dump from its row on, and aborts. This
+/// runs inside the allocator, which mustn't unwind and may be holding a lock, so it only writes
+/// straight to stderr.
+#[cold]
+unsafe fn report(chunk: *const u8, offset: usize, end: usize) -> ! {
+    let mut out = Stderr;
+    let _ = writeln!(
+        out,
+        "alloc_expr: freed memory was written to, chunk {chunk:p}, first modified byte at offset {offset:#x}"
+    );
+
+    let first_row = offset - offset % ROW_SIZE;
+    for row in (first_row..end.min(first_row + DUMP_SIZE)).step_by(ROW_SIZE) {
+        let _ = write!(out, "  {row:#06x}:");
+        for index in row..end.min(row + ROW_SIZE) {
+            let _ = write!(out, " {:02x}", *chunk.add(index));
+        }
+        let _ = writeln!(out);
+    }
+    libc::abort()
+}
+
+/// Formats to stderr through write(2), nothing is buffered or allocated.
+struct Stderr;
+
+impl Write for Stderr {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            match unsafe { libc::write(libc::STDERR_FILENO, bytes.as_ptr().cast(), bytes.len()) } {
+                written if written > 0 => bytes = &bytes[written as usize..],
+                _ => return Err(fmt::Error),
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,14 @@
-use crate::common::{request_memory, PAGE_SIZE};
+use crate::avl_tree::DEFAULT_MAX_CACHED_BYTES;
+use crate::chunk;
+use crate::common::{release_memory, request_memory, PAGE_SIZE};
 use crate::large_allocator::LargeAllocator;
+use crate::stats::{MapCounters, Stats};
 use crate::rb_tree::Colour::{Black, Red};
 use crate::rb_tree::Direction::{Left, Right};
-use std::alloc::Layout;
-use std::ptr::NonNull;
+use core::alloc::Layout;
+use core::marker::PhantomData;
+use core::mem::{offset_of, size_of};
+use core::ptr::NonNull;
 
 #[derive(PartialEq)]
 enum Colour {
@@ -38,26 +43,34 @@ impl From<bool> for Direction {
 
 type NodePtr<T> = Option<NonNull<Node<T>>>;
 
+/// A red-black tree's height is at most twice the log of its size, so no tree that fits in a 64
+/// bit address space is taller than this.
+const MAX_HEIGHT: usize = 128;
+
 struct Node<T: Ord> {
     key: T,
     colour: Colour,
     links: [NodePtr<T>; 2],
+    /// length of the mapping the node sits at the start of, unmapped along with it
+    mapped: usize,
 }
 
 impl<T: Ord> Node<T> {
-    fn new(key: T) -> NonNull<Node<T>> {
+    /// Maps a node for `key`. On failure the errno from mmap is returned.
+    fn new(key: T) -> Result<NonNull<Node<T>>, i32> {
         let layout = Layout::new::<Node<T>>()
             .align_to(PAGE_SIZE)
             .expect("Failed to align layout");
         unsafe {
-            let ptr: NonNull<Node<T>> = request_memory(layout.size()).cast();
+            let ptr: NonNull<Node<T>> = request_memory(layout.size())?.cast();
             let node: Node<T> = Node {
                 key,
                 colour: Colour::Red,
                 links: [None, None],
+                mapped: layout.size(),
             };
             ptr.as_ptr().write(node);
-            ptr
+            Ok(ptr)
         }
     }
 
@@ -70,7 +83,7 @@ impl<T: Ord> Node<T> {
     }
 
     fn is_red(node: NodePtr<T>) -> bool {
-        node.map_or(false, |n| unsafe { n.as_ref().colour == Colour::Red })
+        node.is_some_and(|n| unsafe { n.as_ref().colour == Colour::Red })
     }
 
     fn single_rotation(&mut self, dir: Direction) -> NonNull<Node<T>> {
@@ -95,221 +108,686 @@ impl<T: Ord> Node<T> {
     }
 }
 
+/// Red-black tree using top-down insertion and deletion, so neither needs parent pointers or a
+/// stack. Equal keys are allowed and kept side by side.
+///
+/// As a `LargeAllocator` (`RBTree<Chunk>`) the nodes live at the start of the free chunks
+/// themselves, otherwise `insert` maps a page to hold each node. Freed chunks are cached for
+/// reuse up to `max_cached_bytes` and the largest are unmapped past that, the same as `AVLTree`.
+/// Unlike `AVLTree` a chunk is never split or merged, so a best fit is handed out whole however
+/// much bigger than the request it is.
 pub struct RBTree<T: Ord + Default> {
     root: NodePtr<T>,
+    /// only kept up to date for `RBTree<Chunk>`, the total size of the free chunks
+    free_bytes: usize,
+    /// only used by `RBTree<Chunk>`
+    max_cached_bytes: usize,
+    counters: MapCounters,
 }
 
-impl<T: Ord + Default> RBTree<T> {
-    pub fn new() -> Self {
-        Self { root: None }
+// the tree owns every node linked into it, nothing else points at them
+unsafe impl<T: Ord + Default + Send> Send for RBTree<T> {}
+
+/// In order iterator over the keys of an `RBTree`. It keeps its path through the tree in a fixed
+/// array, so walking the tree never allocates.
+pub struct Iter<'a, T: Ord> {
+    stack: [NodePtr<T>; MAX_HEIGHT],
+    depth: usize,
+    _tree: PhantomData<&'a T>,
+}
+
+impl<T: Ord> Iter<'_, T> {
+    fn push_left(&mut self, mut node: NodePtr<T>) {
+        while let Some(current) = node {
+            self.stack[self.depth] = Some(current);
+            self.depth += 1;
+            node = unsafe { current.as_ref().link(Left) };
+        }
     }
+}
+
+impl<'a, T: Ord> Iterator for Iter<'a, T> {
+    type Item = &'a T;
 
-    pub fn insert(&mut self, key: T) {
-        let node = Node::new(key);
-        unsafe { self.insert_helper(node) };
+    fn next(&mut self) -> Option<Self::Item> {
+        self.depth = self.depth.checked_sub(1)?;
+        let node = unsafe { self.stack[self.depth]?.as_ref() };
+        self.push_left(node.link(Right));
+        Some(&node.key)
     }
+}
 
-    pub fn pop(&mut self, key: &T) -> NodePtr<T> {
-        unsafe { self.pop_helper(key) }
+impl<T: Ord + Default> Default for RBTree<T> {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    unsafe fn insert_helper(&mut self, node: NonNull<Node<T>>) {
-        if self.root.is_none() {
-            self.root = Some(node);
-            return;
+/// Unmaps every node, which for `RBTree<Chunk>` means every cached free chunk.
+impl<T: Ord + Default> Drop for RBTree<T> {
+    fn drop(&mut self) {
+        // rotating left children up until the top node has none lets it go straight away, so
+        // tearing the tree down needs neither a stack nor any rebalancing
+        let mut node = self.root.take();
+        while let Some(mut current) = node {
+            unsafe {
+                match current.as_ref().link(Left) {
+                    Some(mut left) => {
+                        current.as_mut().set_link(Left, left.as_ref().link(Right));
+                        left.as_mut().set_link(Right, Some(current));
+                        node = Some(left);
+                    }
+                    None => {
+                        node = current.as_ref().link(Right);
+                        let mapped = current.as_ref().mapped;
+                        core::ptr::drop_in_place(&raw mut (*current.as_ptr()).key);
+                        release_memory(current.cast(), mapped);
+                    }
+                }
+            }
         }
+    }
+}
 
-        let mut current = self.root;
-        let mut parent: NodePtr<T> = None;
-        let mut grandparent: NodePtr<T> = None;
-        let mut direction = Left;
-        let mut last = direction;
+impl<T: Ord + Default> RBTree<T> {
+    pub const fn new() -> Self {
+        Self {
+            root: None,
+            free_bytes: 0,
+            max_cached_bytes: DEFAULT_MAX_CACHED_BYTES,
+            counters: MapCounters::new(),
+        }
+    }
 
-        let key = &node.as_ref().key;
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
 
-        loop {
-            if current.is_none() {
-                parent.unwrap().as_mut().set_link(direction, Some(node));
-                break;
+    /// Checks the tree's invariants and returns the first one found broken: keys in order, the
+    /// root black, no red node with a red child and the same number of black nodes on every path
+    /// down. A cycle makes the tree taller than it can be, and with distinct keys any node
+    /// reachable twice breaks the ordering, so both are caught. It walks the whole tree, so it's
+    /// meant for tests and debugging.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if Node::is_red(self.root) {
+            return Err("the root is red");
+        }
+        unsafe { Self::validate_subtree(self.root, None, None, 0)? };
+        Ok(())
+    }
+
+    /// Panics if the tree is broken, when the `validate` feature is on in a debug build.
+    fn debug_validate(&self) {
+        if cfg!(all(debug_assertions, feature = "validate")) {
+            if let Err(error) = self.validate() {
+                panic!("RB tree is broken: {error}");
             }
+        }
+    }
 
-            let mut curr_node = current.unwrap().as_mut();
+    /// Checks a subtree whose keys all lie between `above` and `below`, returning its black
+    /// height. Equal keys can end up on either side of each other after rotations, so the
+    /// bounds are inclusive.
+    unsafe fn validate_subtree(
+        node: NodePtr<T>,
+        above: Option<&T>,
+        below: Option<&T>,
+        depth: usize,
+    ) -> Result<usize, &'static str> {
+        let Some(node) = node else {
+            return Ok(1);
+        };
+        if depth >= MAX_HEIGHT {
+            return Err("the tree is taller than a red-black tree can be");
+        }
 
-            if Node::is_red(curr_node.link(Left)) && Node::is_red(curr_node.link(Right)) {
-                curr_node.colour = Colour::Red;
-                curr_node.link(Left).unwrap().as_mut().colour = Colour::Black;
-                curr_node.link(Right).unwrap().as_mut().colour = Colour::Black;
+        let node = node.as_ref();
+        if above.is_some_and(|above| node.key < *above) || below.is_some_and(|below| node.key > *below) {
+            return Err("keys are out of order, or a node is reachable twice");
+        }
+        if node.colour == Red && (Node::is_red(node.link(Left)) || Node::is_red(node.link(Right))) {
+            return Err("a red node has a red child");
+        }
+
+        let left = Self::validate_subtree(node.link(Left), above, Some(&node.key), depth + 1)?;
+        let right = Self::validate_subtree(node.link(Right), Some(&node.key), below, depth + 1)?;
+        if left != right {
+            return Err("paths down the tree pass different numbers of black nodes");
+        }
+        Ok(left + usize::from(node.colour == Black))
+    }
+
+    /// Every key in the tree, smallest first.
+    pub fn iter(&self) -> Iter<'_, T> {
+        let mut iter = Iter {
+            stack: [None; MAX_HEIGHT],
+            depth: 0,
+            _tree: PhantomData,
+        };
+        iter.push_left(self.root);
+        iter
+    }
+
+    /// Adds `key`, next to any equal keys already in the tree. Each key gets its own mapped node,
+    /// if that mapping fails the tree is left as it was and the errno from mmap is returned.
+    pub fn insert(&mut self, key: T) -> Result<(), i32> {
+        let node = Node::new(key)?;
+        unsafe { self.insert_node(node) };
+        self.debug_validate();
+        Ok(())
+    }
+
+    /// Removes the lower bound of `key`, the smallest key that isn't less than it.
+    pub fn pop(&mut self, key: &T) -> Option<T> {
+        unsafe {
+            let node = self.pop_node(key)?;
+            self.debug_validate();
+            let Node { key, mapped, .. } = node.as_ptr().read();
+            release_memory(node.cast(), mapped);
+            Some(key)
+        }
+    }
+
+    /// Unlinks and returns the node holding the lower bound of `key`.
+    unsafe fn pop_node(&mut self, key: &T) -> NodePtr<T> {
+        let target = self.lower_bound(key)?;
+        self.remove_node(target)
+    }
+
+    /// The node with the smallest key that isn't less than `key`.
+    fn lower_bound(&self, key: &T) -> NodePtr<T> {
+        let mut current = self.root;
+        let mut best = None;
+        while let Some(node) = current {
+            let node_ref = unsafe { node.as_ref() };
+            if node_ref.key >= *key {
+                best = current;
             }
+            current = node_ref.link((node_ref.key < *key).into());
+        }
+        best
+    }
+
+    /// Top-down insertion. Walking down from the root, any node with two red children is
+    /// recoloured, and a red node with a red parent is fixed straight away with a rotation at the
+    /// grandparent. By the time the bottom is reached the new red leaf can be attached without
+    /// anything having to be fixed on the way back up.
+    unsafe fn insert_node(&mut self, mut node: NonNull<Node<T>>) {
+        node.as_mut().colour = Red;
+        node.as_mut().links = [None, None];
+
+        let Some(root) = self.root else {
+            node.as_mut().colour = Black;
+            self.root = Some(node);
+            return;
+        };
+
+        // stand in parent for the root, so rotations at the root need no special case
+        let mut head = Node {
+            key: T::default(),
+            colour: Black,
+            links: [None, Some(root)],
+            mapped: 0,
+        };
+
+        // great grandparent, grandparent and parent of the current node
+        let mut great_grandparent = NonNull::from(&mut head);
+        let mut grandparent: NodePtr<T> = None;
+        let mut parent: NodePtr<T> = None;
+        let mut current: NodePtr<T> = Some(root);
+        let mut direction = Left;
+        let mut last = Left;
+
+        loop {
+            let mut curr_node = match current {
+                None => {
+                    parent.unwrap().as_mut().set_link(direction, Some(node));
+                    current = Some(node);
+                    node
+                }
+                Some(mut curr_node) => {
+                    let curr_ref = curr_node.as_mut();
+                    if Node::is_red(curr_ref.link(Left)) && Node::is_red(curr_ref.link(Right)) {
+                        curr_ref.colour = Red;
+                        curr_ref.link(Left).unwrap().as_mut().colour = Black;
+                        curr_ref.link(Right).unwrap().as_mut().colour = Black;
+                    }
+                    curr_node
+                }
+            };
 
             if Node::is_red(current) && Node::is_red(parent) {
-                let dir2 = Direction::from(grandparent.unwrap().as_ref().link(Right) == parent);
-                if current == parent.unwrap().as_ref().link(last) {
-                    grandparent
-                        .unwrap()
-                        .as_mut()
-                        .set_link(dir2, Some(curr_node.single_rotation(last.flip())));
+                let dir2 = Direction::from(great_grandparent.as_ref().link(Right) == grandparent);
+                let grandparent_node = grandparent.unwrap().as_mut();
+                let rotated = if current == parent.unwrap().as_ref().link(last) {
+                    grandparent_node.single_rotation(last.flip())
                 } else {
-                    grandparent
-                        .unwrap()
-                        .as_mut()
-                        .set_link(dir2, Some(curr_node.double_rotation(last.flip())));
-                }
+                    grandparent_node.double_rotation(last.flip())
+                };
+                great_grandparent.as_mut().set_link(dir2, Some(rotated));
             }
 
-            // todo: I need to make this a linked list or munmap here or something to handle duplicate inserts
-            if curr_node.key == *key {
+            if curr_node == node {
                 break;
             }
 
             last = direction;
-            direction = Direction::from(curr_node.key < *key);
+            direction = Direction::from(curr_node.as_ref().key < node.as_ref().key);
 
-            if grandparent.is_some() {
-                grandparent = parent;
+            if let Some(grandparent) = grandparent {
+                great_grandparent = grandparent;
             }
+            grandparent = parent;
             parent = current;
-            current = curr_node.link(direction);
+            current = curr_node.as_mut().link(direction);
         }
 
-        // Final adjustments after insertion
+        self.root = head.link(Right);
         self.root.unwrap().as_mut().colour = Black;
     }
 
-    /// three stage function that should be pulled into three functions
-    /// 1) iteratively walks the tree until finding the lowerbound
-    /// 2) iteratively walks the tree until finding inorder successor (go right, then get minimum)
-    /// 3) rearranges pointers such that the result node isn't the child of anything
-    /// todo: this 90 lines of unsafe code, it can almost certainly be refactored
-    unsafe fn pop_helper(&mut self, key: &T) -> NodePtr<T> {
-        let mut new_node = Node {
+    /// Top-down deletion of `target`, which has to be in the tree. Walking down towards it, a red
+    /// node is pushed along the path with colour flips and rotations, so the node finally
+    /// unlinked at the bottom is red or has a red child and nothing needs fixing afterwards.
+    ///
+    /// The search carries on past the target to its in order predecessor, which has at most one
+    /// child. Chunks can't move, so rather than copying the predecessor's key over the target's
+    /// the predecessor is unlinked and relinked in the target's place. That means keeping track
+    /// of the target's parent, which can change when the path is rotated under it.
+    unsafe fn remove_node(&mut self, target: NonNull<Node<T>>) -> NodePtr<T> {
+        let root = self.root?;
+        let key = &target.as_ref().key;
+
+        // stand in parent for the root, so rotations at the root need no special case
+        let mut head = Node {
             key: T::default(),
-            colour: Red,
-            links: [None, self.root],
+            colour: Black,
+            links: [None, Some(root)],
+            mapped: 0,
         };
-
-        let mut current = NonNull::new(&mut new_node);
-        // parent of current node
-        let mut parent: NodePtr<T> = None;
-        // grandparent of current node
-        let mut grandparent: NodePtr<T> = None;
-        // direction to iterate through tree on next iteration
-        let mut direction = Left;
-        // previous direction
-        let mut last = direction;
-        let mut result: NodePtr<T> = None;
-        let mut result_parent = None;
-        let mut result_direction = Left;
-
-        // eagerly reorder the tree
-        while let Some(node) = current.as_ref() {
-            last = direction;
-            grandparent = parent;
+        let head_ptr = NonNull::from(&mut head);
+
+        let mut current = head_ptr;
+        let mut parent = head_ptr;
+        let mut direction = Right;
+        let mut found: NodePtr<T> = None;
+        let mut found_parent = head_ptr;
+        let mut found_direction = Right;
+
+        while let Some(next) = current.as_ref().link(direction) {
+            let last = direction;
+            let grandparent = parent;
             parent = current;
-            direction = (node.as_ref().key < *key).into();
-
-            // get the lower bound, if it exists
-            if node.as_ref().key == *key
-                || (node.as_ref().key > *key && node.as_ref().links[0].is_none())
-            {
-                result = Some(*node);
-                result_direction = last;
-                result_parent = parent;
-            }
+            current = next;
 
-            current = Some(*node);
+            let curr_node = current.as_mut();
+            direction = Direction::from(curr_node.key < *key);
 
-            let curr_node = current.unwrap().as_mut();
+            if current == target {
+                found = Some(current);
+                found_parent = parent;
+                found_direction = last;
+            }
 
-            if !Node::is_red(current) && Node::is_red(Node::link(curr_node, direction)) {
-                let parent_node = parent.unwrap().as_mut();
+            // push a red node down the path
+            if Node::is_red(Some(current)) || Node::is_red(curr_node.link(direction)) {
+                continue;
+            }
 
-                if Node::is_red(Node::link(curr_node, direction.flip())) {
-                    let rotated = curr_node.single_rotation(direction);
-                    parent_node.set_link(last, Some(rotated));
-                    parent = Some(rotated);
+            if Node::is_red(curr_node.link(direction.flip())) {
+                let rotated = curr_node.single_rotation(direction);
+                parent.as_mut().set_link(last, Some(rotated));
+                if found == Some(current) {
+                    found_parent = rotated;
+                    found_direction = direction;
+                }
+                parent = rotated;
+            } else if let Some(mut sibling) = parent.as_ref().link(last.flip()) {
+                let sibling_node = sibling.as_mut();
+                if !Node::is_red(sibling_node.link(last.flip())) && !Node::is_red(sibling_node.link(last)) {
+                    // colour flip
+                    parent.as_mut().colour = Black;
+                    sibling_node.colour = Red;
+                    curr_node.colour = Red;
                 } else {
-                    let s = parent_node.link(last.flip());
+                    let mut grandparent_node = grandparent;
+                    let dir2 = Direction::from(grandparent_node.as_ref().link(Right) == Some(parent));
 
-                    if s.is_none() {
-                        continue;
-                    }
-                    let s_node = s.unwrap().as_mut();
-                    if !Node::is_red(s_node.links[0]) && Node::is_red(s_node.links[1]) {
-                        parent_node.colour = Black;
-                        s_node.colour = Red;
-                        curr_node.colour = Red;
+                    let mut rotated = if Node::is_red(sibling_node.link(last)) {
+                        parent.as_mut().double_rotation(last)
                     } else {
-                        let gp_node = grandparent.unwrap().as_mut();
-                        let new_dir = (gp_node.links[1] == parent).into();
-
-                        let rotated = if Node::is_red(s_node.link(last)) {
-                            parent_node.double_rotation(last)
-                        } else {
-                            parent_node.single_rotation(last)
-                        };
-
-                        gp_node.set_link(new_dir, Some(rotated));
-
-                        let node = gp_node.link(new_dir).unwrap().as_mut();
-                        // rotate colours here
-                        curr_node.colour = Red;
-                        node.colour = Red;
-                        node.links[0].unwrap().as_mut().colour = Black;
-                        node.links[1].unwrap().as_mut().colour = Black;
+                        parent.as_mut().single_rotation(last)
+                    };
+                    grandparent_node.as_mut().set_link(dir2, Some(rotated));
+                    if found == Some(parent) {
+                        found_parent = rotated;
+                        found_direction = last;
                     }
+
+                    // rotate colours here
+                    let rotated_node = rotated.as_mut();
+                    curr_node.colour = Red;
+                    rotated_node.colour = Red;
+                    rotated_node.link(Left).unwrap().as_mut().colour = Black;
+                    rotated_node.link(Right).unwrap().as_mut().colour = Black;
                 }
             }
         }
 
-        // at this point the target has been found, clean up links
-        if result.is_some() {
-            Self::extract_node(
-                result_parent.unwrap().as_mut(),
-                result_direction,
-                current.unwrap().as_mut(),
-                parent.unwrap().as_mut(),
-                result.unwrap().as_mut(),
-            )
+        if let Some(mut target) = found {
+            // unlink the last node on the path, it has at most one child
+            let curr_node = current.as_mut();
+            let child = curr_node.link(curr_node.link(Left).is_none().into());
+            let parent_node = parent.as_mut();
+            parent_node.set_link((parent_node.link(Right) == Some(current)).into(), child);
+
+            // and put it where the target was
+            if current != target {
+                let target_node = target.as_mut();
+                curr_node.links = target_node.links;
+                curr_node.colour = core::mem::replace(&mut target_node.colour, Red);
+                found_parent.as_mut().set_link(found_direction, Some(current));
+            }
+            target.as_mut().links = [None, None];
         }
 
-        result
-    }
-
-    /// this swaps pointers around the tree for an efficient node removal. The parent of the
-    /// target node now points to the in order successor, and the parent of the successor now points
-    /// to the child (if it exists) of the in order successor.
-    unsafe fn extract_node(
-        result_parent: *mut Node<T>,
-        result_dir: Direction,
-        current: &mut Node<T>,
-        parent: *mut Node<T>,
-        result: *mut Node<T>,
-    ) {
-        // null check. todo: prove this is a waste of an if check
-        if result_parent.is_null() || parent.is_null() || result.is_null() {
-            return;
+        self.root = head.link(Right);
+        if let Some(mut root) = self.root {
+            root.as_mut().colour = Black;
         }
-        // update the result parent pointer to point to the current node, which at this point
-        // is the inorder successor
-        (*result_parent).set_link(result_dir, NonNull::new(current));
-        // if a child exists it exists in at maximum one branch, this finds the direction
-        let current_child_direction = current.links[0].is_none().into();
-        // update parent of successor node to point to potential current node children
-        let current_child = current.link(current_child_direction);
-
-        match (*parent).link(Left) {
-            // current child is on the right
-            None => {
-                (*parent).links[1] = current_child;
+
+        found
+    }
+}
+
+/// Key of a free chunk in an `RBTree` large allocator. Chunks are ordered by size and then by
+/// address, so every key is unique and the lower bound of a size with address zero is the best
+/// fitting chunk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Chunk {
+    size: usize,
+    address: usize,
+}
+
+impl Chunk {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn address(&self) -> usize {
+        self.address
+    }
+}
+
+impl RBTree<Chunk> {
+    /// A tree that unmaps chunks once more than `max_cached_bytes` are free, zero gives every
+    /// chunk back as soon as it's freed.
+    pub const fn with_max_cached_bytes(max_cached_bytes: usize) -> Self {
+        Self { root: None, free_bytes: 0, max_cached_bytes, counters: MapCounters::new() }
+    }
+
+    pub fn max_cached_bytes(&self) -> usize {
+        self.max_cached_bytes
+    }
+
+    pub fn set_max_cached_bytes(&mut self, max_cached_bytes: usize) {
+        self.max_cached_bytes = max_cached_bytes;
+        self.release_excess();
+        self.debug_validate_chunks();
+    }
+
+    /// Bytes sitting free in the tree.
+    pub fn cached_bytes(&self) -> usize {
+        self.free_bytes
+    }
+
+    /// `validate`, and also that every chunk is where its key says with no key in the tree twice,
+    /// and that the free bytes add up.
+    pub fn validate_chunks(&self) -> Result<(), &'static str> {
+        self.validate()?;
+
+        let mut bytes = 0;
+        let mut last = None;
+        for chunk in self.iter() {
+            if last.is_some_and(|last| last >= *chunk) {
+                return Err("a chunk is in the tree twice");
+            }
+            if chunk as *const Chunk as usize - offset_of!(Node<Chunk>, key) != chunk.address {
+                return Err("a chunk's key has the wrong address");
+            }
+            if chunk.size == 0 || !chunk.size.is_multiple_of(PAGE_SIZE) {
+                return Err("a chunk isn't a whole number of pages");
             }
+            bytes += chunk.size;
+            last = Some(*chunk);
+        }
+        if bytes != self.free_bytes {
+            return Err("free_bytes doesn't match the free chunks");
+        }
+        Ok(())
+    }
+
+    /// Panics if the tree is broken, when the `validate` feature is on in a debug build.
+    fn debug_validate_chunks(&self) {
+        if cfg!(all(debug_assertions, feature = "validate")) {
+            if let Err(error) = self.validate_chunks() {
+                panic!("RB tree is broken: {error}");
+            }
+        }
+    }
+
+    /// Where every free chunk starts and its length, smallest first.
+    pub fn free_chunks(&self) -> impl Iterator<Item = (NonNull<u8>, usize)> + '_ {
+        self.iter()
+            .map(|chunk| (unsafe { NonNull::new_unchecked(chunk.address as *mut u8) }, chunk.size))
+    }
+
+    /// Calls `visit` with the start and length of every free chunk, smallest first. Neither this
+    /// nor `free_chunks` allocates, so either can run inside the global allocator.
+    pub fn for_each_free_chunk(&self, mut visit: impl FnMut(NonNull<u8>, usize)) {
+        for (address, size) in self.free_chunks() {
+            visit(address, size);
+        }
+    }
+
+    /// Unmaps the largest free chunks until the tree is back under its cap.
+    fn release_excess(&mut self) {
+        while self.free_bytes > self.max_cached_bytes {
+            let mut largest = self.root.unwrap();
+            while let Some(right) = unsafe { largest.as_ref().link(Right) } {
+                largest = right;
+            }
+            unsafe {
+                let key = largest.as_ref().key;
+                let node = self.pop_node(&key).unwrap();
+                self.release(node);
+            }
+        }
+    }
+
+    /// Unmaps a chunk that has already been taken out of the tree.
+    unsafe fn release(&mut self, node: NonNull<Node<Chunk>>) {
+        let size = node.as_ref().key.size;
+        self.free_bytes -= size;
+        release_memory(node.cast(), size);
+        self.counters.unmapped(size);
+    }
+
+    /// Bytes from `ptr` to the end of its chunk.
+    unsafe fn capacity(node: NonNull<Node<Chunk>>, ptr: *mut u8) -> usize {
+        node.as_ref().key.size - (ptr as usize - node.as_ptr() as usize)
+    }
+}
+
+unsafe impl LargeAllocator for RBTree<Chunk> {
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let Some(size) = chunk::chunk_size(size_of::<Node<Chunk>>(), layout) else {
+            return core::ptr::null_mut();
+        };
+
+        let node = match self.pop_node(&Chunk { size, address: 0 }) {
             Some(node) => {
-                let direction = (node.as_ptr() == current).into();
-                (*parent).set_link(direction, current_child);
+                self.free_bytes -= node.as_ref().key.size;
+                node
             }
+            None => {
+                let Some((address, size)) = chunk::map(size_of::<Node<Chunk>>(), layout, &mut self.counters) else {
+                    return core::ptr::null_mut();
+                };
+                let node: NonNull<Node<Chunk>> = address.cast();
+                node.as_ptr().write(Node {
+                    key: Chunk { size, address: address.as_ptr() as usize },
+                    colour: Red,
+                    links: [None, None],
+                    mapped: size,
+                });
+                node
+            }
+        };
+        self.debug_validate_chunks();
+        chunk::place_data(node.cast(), size_of::<Node<Chunk>>(), layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        assert!(!ptr.is_null(), "Attempted to deallocate a null pointer.");
+
+        let node: NonNull<Node<Chunk>> = chunk::from_data(ptr).cast();
+        self.free_bytes += node.as_ref().key.size;
+        self.insert_node(node);
+        self.release_excess();
+        self.debug_validate_chunks();
+    }
+
+    unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        assert!(!ptr.is_null(), "Attempted to reallocate a null pointer.");
+
+        let node: NonNull<Node<Chunk>> = chunk::from_data(ptr).cast();
+        if Self::capacity(node, ptr) >= new_size {
+            return ptr;
+        }
+
+        let Ok(new_layout) = Layout::from_size_align(new_size, layout.align()) else {
+            return core::ptr::null_mut();
         };
+        let new_ptr = self.alloc(new_layout);
+        if new_ptr.is_null() {
+            return core::ptr::null_mut();
+        }
+
+        core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size());
+        self.dealloc(ptr);
+
+        new_ptr
+    }
+
+    unsafe fn usable_size(&self, ptr: *mut u8, _layout: Layout) -> usize {
+        Self::capacity(chunk::from_data(ptr).cast(), ptr)
+    }
+
+    fn trim(&mut self) {
+        while let Some(node) = unsafe { self.pop_node(&Chunk::default()) } {
+            unsafe { self.release(node) };
+        }
+        self.debug_validate_chunks();
+    }
+
+    fn stats(&self) -> Stats {
+        self.counters.stats(self.free_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Insert(u8),
+        /// pop the lower bound of the key
+        Pop(u8),
+        /// pop the lower bound of the root's key
+        PopRoot,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        // a narrow key range, so there are plenty of duplicates
+        prop_oneof![
+            3 => (0..32u8).prop_map(Op::Insert),
+            2 => (0..32u8).prop_map(Op::Pop),
+            1 => Just(Op::PopRoot),
+        ]
+    }
+
+    /// Pops the lower bound of `key` out of the model.
+    fn model_pop(model: &mut BTreeMap<u8, usize>, key: u8) -> Option<u8> {
+        let (&key, count) = model.range_mut(key..).next()?;
+        *count -= 1;
+        if *count == 0 {
+            model.remove(&key);
+        }
+        Some(key)
+    }
+
+    proptest! {
+        #[test]
+        fn matches_btreemap(ops in prop::collection::vec(op(), 1..200)) {
+            let mut tree = RBTree::new();
+            // key to number of copies in the tree
+            let mut model = BTreeMap::new();
+
+            for op in ops {
+                match op {
+                    Op::Insert(key) => {
+                        tree.insert(key).unwrap();
+                        *model.entry(key).or_insert(0) += 1;
+                    }
+                    Op::Pop(key) => prop_assert_eq!(tree.pop(&key), model_pop(&mut model, key)),
+                    Op::PopRoot => {
+                        let key = tree.root.map_or(0, |root| unsafe { root.as_ref().key });
+                        prop_assert_eq!(tree.pop(&key), model_pop(&mut model, key));
+                    }
+                }
+
+                prop_assert_eq!(tree.validate(), Ok(()));
+                let keys: Vec<_> = tree.iter().copied().collect();
+                let expected: Vec<_> = model.iter().flat_map(|(&key, &count)| std::iter::repeat_n(key, count)).collect();
+                prop_assert_eq!(keys, expected);
+            }
+
+            while tree.pop(&0).is_some() {}
+            prop_assert!(tree.is_empty());
+        }
+    }
+
+    /// A layout whose chunk is exactly `pages` pages, the node fits in the half page left over.
+    fn pages(pages: usize) -> Layout {
+        Layout::from_size_align(pages * PAGE_SIZE - PAGE_SIZE / 2, 8).unwrap()
+    }
+
+    #[test]
+    fn largest_chunks_are_evicted_past_the_cap() {
+        let mut tree = RBTree::with_max_cached_bytes(12 * PAGE_SIZE);
+        unsafe {
+            let [two, four, eight, sixteen] = [2, 4, 8, 16].map(|count| tree.alloc(pages(count)));
+
+            tree.dealloc(sixteen);
+            assert_eq!((tree.stats().munmap_calls, tree.cached_bytes()), (1, 0));
+
+            tree.dealloc(two);
+            tree.dealloc(eight);
+            tree.dealloc(four);
+            assert_eq!((tree.stats().munmap_calls, tree.cached_bytes()), (2, 6 * PAGE_SIZE));
+            let sizes: Vec<_> = tree.free_chunks().map(|(_, size)| size).collect();
+            assert_eq!(sizes, [2 * PAGE_SIZE, 4 * PAGE_SIZE]);
+            assert_eq!(tree.validate_chunks(), Ok(()));
+        }
 
-        // finally, update what the current node points to
-        current.links[0] = (*result).link(Left);
-        current.links[1] = (*result).link(Right);
+        tree.set_max_cached_bytes(0);
+        assert_eq!((tree.stats().munmap_calls, tree.stats().mapped_bytes), (4, 0));
+        assert!(tree.is_empty());
     }
 }
@@ -0,0 +1,175 @@
+use core::alloc::Layout;
+
+use crate::common::PAGE_SIZE;
+
+/// Every size class is a multiple of this, and requests are looked up in steps of it.
+pub const QUANTUM: usize = 16;
+
+/// Largest object a size class can hold. Objects are carved out of pages, so anything bigger
+/// belongs in the large allocator.
+pub const MAX_SMALL_SIZE: usize = PAGE_SIZE;
+
+const LOOKUP_LEN: usize = MAX_SMALL_SIZE / QUANTUM + 1;
+
+/// The object sizes served by an `Allocator`'s segregated lists, in ascending order.
+///
+/// Tables are built in const context so a `static` allocator can carry its own, and a bad table
+/// fails at compile time. Finding the class for a request is a single index into a lookup table
+/// rather than a search through the sizes.
+#[derive(Clone, Copy, Debug)]
+pub struct SizeClasses<const N: usize> {
+    sizes: [usize; N],
+    /// class index for each multiple of `QUANTUM` up to the largest class, rounding up
+    lookup: [u8; LOOKUP_LEN],
+}
+
+impl<const N: usize> SizeClasses<N> {
+    /// Builds a table from explicit sizes. They have to be strictly ascending multiples of
+    /// `QUANTUM` no larger than `MAX_SMALL_SIZE`.
+    pub const fn new(sizes: [usize; N]) -> Self {
+        assert!(N > 0 && N <= u8::MAX as usize, "a table needs between 1 and 255 size classes");
+
+        let mut lookup = [0; LOOKUP_LEN];
+        let mut class = 0;
+        let mut index = 0;
+        while class < N {
+            let size = sizes[class];
+            assert!(size > 0 && size.is_multiple_of(QUANTUM), "size classes must be multiples of 16");
+            assert!(size <= MAX_SMALL_SIZE, "size classes can't be larger than MAX_SMALL_SIZE");
+            assert!(class == 0 || sizes[class - 1] < size, "size classes must be ascending");
+
+            while index * QUANTUM <= size {
+                lookup[index] = class as u8;
+                index += 1;
+            }
+            class += 1;
+        }
+
+        SizeClasses { sizes, lookup }
+    }
+
+    /// `N` classes doubling from `smallest`, which has to be a power of two. Wastes up to half an
+    /// object, but every object is aligned to its own size.
+    pub const fn powers_of_two(smallest: usize) -> Self {
+        assert!(smallest.is_power_of_two(), "the smallest class must be a power of two");
+
+        let mut sizes = [0; N];
+        let mut class = 0;
+        while class < N {
+            sizes[class] = smallest << class;
+            class += 1;
+        }
+        Self::new(sizes)
+    }
+
+    /// `N` classes with `steps` evenly spaced classes per doubling, the way jemalloc spaces its
+    /// small classes. Starting from `QUANTUM`, each class is the last plus the larger of `QUANTUM`
+    /// and the last class's power of two split `steps` ways, which bounds the space wasted by
+    /// rounding a request up to roughly `1 / steps`.
+    pub const fn geometric(steps: usize) -> Self {
+        assert!(steps.is_power_of_two(), "steps per doubling must be a power of two");
+
+        let mut sizes = [0; N];
+        let mut size = QUANTUM;
+        let mut class = 0;
+        while class < N {
+            sizes[class] = size;
+
+            // largest power of two that fits in the current size
+            let group = 1 << (usize::BITS - 1 - size.leading_zeros());
+            let spacing = group / steps;
+            size += if spacing > QUANTUM { spacing } else { QUANTUM };
+            class += 1;
+        }
+        Self::new(sizes)
+    }
+
+    /// Object size of `class`.
+    pub const fn size(&self, class: usize) -> usize {
+        self.sizes[class]
+    }
+
+    /// Size of the biggest class, anything larger goes to the large allocator.
+    pub const fn largest(&self) -> usize {
+        self.sizes[N - 1]
+    }
+
+    /// The class whose objects can hold `layout`, None if it has to go to the large allocator.
+    ///
+    /// Objects are packed back to back from the start of a page, so an object is aligned to the
+    /// largest power of two dividing its class size. Rounding the request up to its alignment
+    /// first means power of two classes always fit. Any other class has to be checked, and if it
+    /// isn't aligned enough the next class up that is serves the request instead.
+    pub fn class_of(&self, layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        if size > self.largest() {
+            return None;
+        }
+
+        let mut class = self.lookup[size.div_ceil(QUANTUM)] as usize;
+        while !self.sizes[class].is_multiple_of(layout.align()) {
+            class += 1;
+            if class == N {
+                return None;
+            }
+        }
+        Some(class)
+    }
+}
+
+/// The table used by `Allocator::new`, seven classes from 16 bytes to 1 KiB.
+pub const DEFAULT_SIZE_CLASSES: SizeClasses<7> = SizeClasses::powers_of_two(16);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn geometric_spacing() {
+        let classes = SizeClasses::<12>::geometric(4);
+        let sizes: Vec<_> = (0..12).map(|class| classes.size(class)).collect();
+        assert_eq!(sizes, [16, 32, 48, 64, 80, 96, 112, 128, 160, 192, 224, 256]);
+        assert_eq!(classes.largest(), 256);
+    }
+
+    #[test]
+    fn rounds_up_at_each_boundary() {
+        let classes = SizeClasses::<12>::geometric(4);
+        let mut smallest = 0;
+        for class in 0..12 {
+            let size = classes.size(class);
+            assert_eq!(classes.class_of(layout(smallest + 1, 1)), Some(class), "{} bytes", smallest + 1);
+            assert_eq!(classes.class_of(layout(size, 1)), Some(class), "{size} bytes");
+            smallest = size;
+        }
+        assert_eq!(classes.class_of(layout(257, 1)), None);
+        assert_eq!(classes.class_of(layout(0, 1)), Some(0));
+    }
+
+    #[test]
+    fn misaligned_classes_are_skipped() {
+        let classes = SizeClasses::<12>::geometric(4);
+        // 48 is only 16 byte aligned, 64 is the next class that's aligned to 32
+        assert_eq!(classes.class_of(layout(48, 16)), Some(2));
+        assert_eq!(classes.class_of(layout(48, 32)), Some(3));
+        // 160, 192 and 224 aren't aligned to 128, so the only fit is 256
+        assert_eq!(classes.class_of(layout(130, 128)), Some(11));
+        // nothing in the table is aligned to 512
+        assert_eq!(classes.class_of(layout(200, 512)), None);
+    }
+
+    #[test]
+    fn powers_of_two_fit_their_alignment() {
+        let classes = DEFAULT_SIZE_CLASSES;
+        for class in 0..7 {
+            let size = classes.size(class);
+            assert_eq!(classes.class_of(layout(size / 2, size)), Some(class));
+            assert_eq!(classes.class_of(layout(size, size)), Some(class));
+        }
+        assert_eq!(classes.class_of(layout(1, 2048)), None);
+    }
+}
@@ -0,0 +1,164 @@
+use core::ops::Add;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::Relaxed;
+
+/// Snapshot of how much memory an allocator holds and how it came by it.
+///
+/// Small objects are carved out of pages that are never unmapped, and a refill rarely divides
+/// evenly into objects, so for them `allocated_bytes + free_bytes` can fall a little short of
+/// `mapped_bytes`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// bytes currently mapped from the OS
+    pub mapped_bytes: usize,
+    /// bytes handed to the application, counted in whole objects or chunks
+    pub allocated_bytes: usize,
+    /// bytes sitting free in lists, thread caches and trees
+    pub free_bytes: usize,
+    pub mmap_calls: usize,
+    pub munmap_calls: usize,
+    pub mremap_calls: usize,
+}
+
+impl Add for Stats {
+    type Output = Stats;
+
+    fn add(self, other: Stats) -> Stats {
+        Stats {
+            mapped_bytes: self.mapped_bytes + other.mapped_bytes,
+            allocated_bytes: self.allocated_bytes + other.allocated_bytes,
+            free_bytes: self.free_bytes + other.free_bytes,
+            mmap_calls: self.mmap_calls + other.mmap_calls,
+            munmap_calls: self.munmap_calls + other.munmap_calls,
+            mremap_calls: self.mremap_calls + other.mremap_calls,
+        }
+    }
+}
+
+/// Objects of a single size class.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    /// object size of the class
+    pub size: usize,
+    /// objects handed to the application
+    pub allocated: usize,
+    /// objects in the class's shared list or any thread's cache
+    pub free: usize,
+}
+
+/// Snapshot of an `Allocator`, the segregated lists and the large allocator apart.
+#[derive(Clone, Copy, Debug)]
+pub struct AllocatorStats<const N: usize> {
+    /// everything served from the segregated lists
+    pub small: Stats,
+    pub classes: [ClassStats; N],
+    pub large: Stats,
+}
+
+impl<const N: usize> AllocatorStats<N> {
+    /// The small and large sides added together.
+    pub fn total(&self) -> Stats {
+        self.small + self.large
+    }
+}
+
+/// Mappings made and given back by a large allocator. It only changes behind the allocator's
+/// lock, so plain integers do.
+pub(crate) struct MapCounters {
+    mapped_bytes: usize,
+    mmap_calls: usize,
+    munmap_calls: usize,
+    mremap_calls: usize,
+}
+
+impl MapCounters {
+    pub const fn new() -> Self {
+        MapCounters { mapped_bytes: 0, mmap_calls: 0, munmap_calls: 0, mremap_calls: 0 }
+    }
+
+    pub fn mapped(&mut self, length: usize) {
+        self.mapped_bytes += length;
+        self.mmap_calls += 1;
+    }
+
+    pub fn unmapped(&mut self, length: usize) {
+        self.mapped_bytes -= length;
+        self.munmap_calls += 1;
+    }
+
+    pub fn remapped(&mut self, old_length: usize, new_length: usize) {
+        self.mapped_bytes = self.mapped_bytes - old_length + new_length;
+        self.mremap_calls += 1;
+    }
+
+    /// Everything mapped is either free or handed out, so `free_bytes` is all that's missing.
+    pub fn stats(&self, free_bytes: usize) -> Stats {
+        Stats {
+            mapped_bytes: self.mapped_bytes,
+            allocated_bytes: self.mapped_bytes - free_bytes,
+            free_bytes,
+            mmap_calls: self.mmap_calls,
+            munmap_calls: self.munmap_calls,
+            mremap_calls: self.mremap_calls,
+        }
+    }
+}
+
+/// A size class's shared list, mirrored into atomics whenever it changes under its lock so stats
+/// can be read without taking the lock.
+pub(crate) struct ClassCounters {
+    /// times the list has mapped fresh pages
+    pub refills: AtomicUsize,
+    /// objects in the list
+    pub free: AtomicUsize,
+}
+
+impl ClassCounters {
+    pub const fn new() -> Self {
+        ClassCounters { refills: AtomicUsize::new(0), free: AtomicUsize::new(0) }
+    }
+}
+
+/// A large allocator's stats, mirrored into atomics after every change made under its lock so
+/// they can be read without taking the lock.
+pub(crate) struct LargeCounters {
+    mapped_bytes: AtomicUsize,
+    allocated_bytes: AtomicUsize,
+    free_bytes: AtomicUsize,
+    mmap_calls: AtomicUsize,
+    munmap_calls: AtomicUsize,
+    mremap_calls: AtomicUsize,
+}
+
+impl LargeCounters {
+    pub const fn new() -> Self {
+        LargeCounters {
+            mapped_bytes: AtomicUsize::new(0),
+            allocated_bytes: AtomicUsize::new(0),
+            free_bytes: AtomicUsize::new(0),
+            mmap_calls: AtomicUsize::new(0),
+            munmap_calls: AtomicUsize::new(0),
+            mremap_calls: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn store(&self, stats: Stats) {
+        self.mapped_bytes.store(stats.mapped_bytes, Relaxed);
+        self.allocated_bytes.store(stats.allocated_bytes, Relaxed);
+        self.free_bytes.store(stats.free_bytes, Relaxed);
+        self.mmap_calls.store(stats.mmap_calls, Relaxed);
+        self.munmap_calls.store(stats.munmap_calls, Relaxed);
+        self.mremap_calls.store(stats.mremap_calls, Relaxed);
+    }
+
+    pub fn load(&self) -> Stats {
+        Stats {
+            mapped_bytes: self.mapped_bytes.load(Relaxed),
+            allocated_bytes: self.allocated_bytes.load(Relaxed),
+            free_bytes: self.free_bytes.load(Relaxed),
+            mmap_calls: self.mmap_calls.load(Relaxed),
+            munmap_calls: self.munmap_calls.load(Relaxed),
+            mremap_calls: self.mremap_calls.load(Relaxed),
+        }
+    }
+}
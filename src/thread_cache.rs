@@ -0,0 +1,175 @@
+use core::cell::UnsafeCell;
+#[cfg(feature = "std")]
+use core::cell::Cell;
+#[cfg(feature = "std")]
+use core::ffi::c_void;
+#[cfg(feature = "std")]
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::Relaxed;
+#[cfg(feature = "std")]
+use core::sync::atomic::Ordering::{AcqRel, Acquire};
+
+use crate::linked_list::FreeList;
+
+/// How many threads can hold a cache at once, any past this go straight to the shared lists.
+pub const MAX_THREADS: usize = 128;
+
+/// Free objects a thread keeps per size class before giving some back.
+pub const CACHE_CAPACITY: usize = 64;
+
+/// Objects moved between a thread's cache and a shared list in one go.
+pub const BATCH_SIZE: usize = CACHE_CAPACITY / 2;
+
+/// the thread hasn't asked for a cache index yet
+#[cfg(feature = "std")]
+const UNCLAIMED: usize = usize::MAX;
+/// the thread has no cache index, either they had all been taken or the thread is exiting
+#[cfg(feature = "std")]
+const NO_INDEX: usize = usize::MAX - 1;
+
+/// Free objects for each size class, kept by a single thread.
+///
+/// Every allocator holds one cache per thread index, and each index belongs to one live thread
+/// at a time, so the cache needs neither a lock nor atomics. When a thread exits its index goes
+/// back into the pool and the next thread to claim it inherits whatever is still cached. The
+/// objects stay with the allocator they came from either way, so nothing is leaked or handed
+/// to a different allocator.
+#[repr(align(64))]
+pub struct ThreadCache<const N: usize> {
+    bins: UnsafeCell<[FreeList; N]>,
+    /// length of each bin, written by the owning thread so other threads can read stats
+    cached: [AtomicUsize; N],
+}
+
+// only the thread holding the cache's index ever touches it
+unsafe impl<const N: usize> Sync for ThreadCache<N> {}
+
+impl<const N: usize> ThreadCache<N> {
+    pub const fn new() -> Self {
+        ThreadCache {
+            bins: UnsafeCell::new([const { FreeList::new() }; N]),
+            cached: [const { AtomicUsize::new(0) }; N],
+        }
+    }
+
+    /// Objects in the bin for `class`, as of the owning thread's last update.
+    pub fn cached(&self, class: usize) -> usize {
+        self.cached[class].load(Relaxed)
+    }
+
+    /// Publishes the length of the bin for `class`, only for the thread holding the index.
+    pub fn update_cached(&self, class: usize, len: usize) {
+        self.cached[class].store(len, Relaxed);
+    }
+
+    /// # Safety
+    ///
+    /// Only the thread currently holding this cache's index may call this, and the reference
+    /// can't be held across another call.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn bins(&self) -> &mut [FreeList; N] {
+        &mut *self.bins.get()
+    }
+}
+
+/// Without std there are no thread locals to keep a thread's index in, so every thread goes to
+/// the shared lists.
+#[cfg(not(feature = "std"))]
+pub fn current_index() -> Option<usize> {
+    None
+}
+
+#[cfg(feature = "std")]
+thread_local! {
+    static INDEX: Cell<usize> = const { Cell::new(UNCLAIMED) };
+}
+
+/// One bit per thread index, set while a thread holds it.
+#[cfg(feature = "std")]
+static CLAIMED: [AtomicU64; MAX_THREADS / 64] = [const { AtomicU64::new(0) }; MAX_THREADS / 64];
+
+/// pthread key whose destructor gives a thread's index back, plus one so zero means it hasn't
+/// been created yet.
+#[cfg(feature = "std")]
+static EXIT_KEY: AtomicUsize = AtomicUsize::new(0);
+
+/// Index of the calling thread's cache, claiming one the first time a thread asks. None means
+/// the thread has to use the shared lists.
+#[cfg(feature = "std")]
+pub fn current_index() -> Option<usize> {
+    let index = INDEX.get();
+    match index {
+        UNCLAIMED => {
+            // pthread may allocate while the index is being claimed, those allocations have to
+            // go to the shared lists rather than try to claim another index
+            INDEX.set(NO_INDEX);
+            let claimed = claim_index();
+            INDEX.set(claimed.unwrap_or(NO_INDEX));
+            claimed
+        }
+        NO_INDEX => None,
+        index => Some(index),
+    }
+}
+
+#[cfg(feature = "std")]
+fn claim_index() -> Option<usize> {
+    let key = exit_key()?;
+
+    for (word, bits) in CLAIMED.iter().enumerate() {
+        let mut current = bits.load(Relaxed);
+        while current != u64::MAX {
+            let bit = current.trailing_ones() as usize;
+            match bits.compare_exchange_weak(current, current | 1 << bit, Acquire, Relaxed) {
+                Ok(_) => {
+                    let index = word * 64 + bit;
+                    // the value only has to be non-null for the destructor to run
+                    if unsafe { libc::pthread_setspecific(key, (index + 1) as *const c_void) } != 0 {
+                        release_index(index);
+                        return None;
+                    }
+                    return Some(index);
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+    None
+}
+
+#[cfg(feature = "std")]
+fn release_index(index: usize) {
+    CLAIMED[index / 64].fetch_and(!(1 << (index % 64)), AcqRel);
+}
+
+/// Runs as the thread exits. Anything the thread allocates or frees after this point goes to the
+/// shared lists, as another thread may already be using the cache.
+#[cfg(feature = "std")]
+unsafe extern "C" fn release_on_exit(value: *mut c_void) {
+    INDEX.set(NO_INDEX);
+    release_index(value as usize - 1);
+}
+
+/// Creates the exit key the first time it's needed, None if pthread couldn't make one.
+#[cfg(feature = "std")]
+fn exit_key() -> Option<libc::pthread_key_t> {
+    match EXIT_KEY.load(Acquire) {
+        0 => {}
+        key => return Some((key - 1) as libc::pthread_key_t),
+    }
+
+    let mut key: libc::pthread_key_t = 0;
+    if unsafe { libc::pthread_key_create(&mut key, Some(release_on_exit)) } != 0 {
+        return None;
+    }
+
+    // another thread may have created one at the same time, in which case use theirs
+    match EXIT_KEY.compare_exchange(0, key as usize + 1, AcqRel, Acquire) {
+        Ok(_) => Some(key),
+        Err(existing) => {
+            unsafe { libc::pthread_key_delete(key) };
+            Some((existing - 1) as libc::pthread_key_t)
+        }
+    }
+}
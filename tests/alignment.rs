@@ -0,0 +1,106 @@
+use std::alloc::{GlobalAlloc, Layout};
+
+use alloc_expr::{AVLTree, Allocator, LargeAllocator};
+
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+const SIZES: [usize; 4] = [1, 4095, 4096, 70_001];
+
+/// Every power of two from 1 up to a huge page.
+fn alignments() -> impl Iterator<Item = usize> {
+    (0..=HUGE_PAGE_SIZE.trailing_zeros()).map(|shift| 1 << shift)
+}
+
+unsafe fn fill(ptr: *mut u8, size: usize, seed: u8) {
+    for offset in 0..size {
+        ptr.add(offset).write(seed.wrapping_add(offset as u8));
+    }
+}
+
+unsafe fn check(ptr: *mut u8, size: usize, seed: u8) {
+    for offset in 0..size {
+        assert_eq!(*ptr.add(offset), seed.wrapping_add(offset as u8), "byte {offset} changed");
+    }
+}
+
+#[test]
+fn avl_tree_aligns_fresh_chunks() {
+    let mut tree = AVLTree::new();
+    for align in alignments() {
+        for size in SIZES {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            unsafe {
+                let ptr = tree.alloc(layout);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0, "{layout:?} is misaligned");
+
+                fill(ptr, size, align as u8);
+                check(ptr, size, align as u8);
+                tree.dealloc(ptr);
+            }
+        }
+    }
+    tree.trim();
+}
+
+#[test]
+fn avl_tree_realigns_reused_chunks() {
+    let mut tree = AVLTree::new();
+    for align in alignments() {
+        unsafe {
+            // leave a chunk big enough for the aligned request in the tree
+            let chunk = tree.alloc(Layout::from_size_align(2 * HUGE_PAGE_SIZE, 8).unwrap());
+            tree.dealloc(chunk);
+
+            let layout = Layout::from_size_align(HUGE_PAGE_SIZE / 2, align).unwrap();
+            let ptr = tree.alloc(layout);
+            assert_eq!(ptr as usize % align, 0, "{layout:?} is misaligned");
+
+            fill(ptr, layout.size(), 7);
+            tree.dealloc(ptr);
+        }
+        tree.trim();
+    }
+}
+
+#[test]
+fn avl_tree_realloc_keeps_alignment_and_contents() {
+    let mut tree = AVLTree::new();
+    for align in alignments() {
+        let layout = Layout::from_size_align(10_000, align).unwrap();
+        unsafe {
+            let ptr = tree.alloc(layout);
+            fill(ptr, layout.size(), 3);
+
+            let grown = tree.realloc(ptr, layout, 300_000);
+            assert_eq!(grown as usize % align, 0, "{layout:?} is misaligned after growing");
+            check(grown, layout.size(), 3);
+
+            tree.dealloc(grown);
+        }
+    }
+    tree.trim();
+}
+
+#[test]
+fn allocator_aligns_large_allocations() {
+    let allocator: Allocator<AVLTree> = Allocator::new();
+    for align in alignments() {
+        // a small size with an alignment past the largest class still has to go large
+        for size in [8, 2000, 70_001] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            unsafe {
+                let ptr = allocator.alloc(layout);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0, "{layout:?} is misaligned");
+
+                fill(ptr, size, 11);
+                let grown = allocator.realloc(ptr, layout, size * 3);
+                assert_eq!(grown as usize % align, 0, "{layout:?} is misaligned after growing");
+                check(grown, size, 11);
+
+                allocator.dealloc(grown, Layout::from_size_align(size * 3, align).unwrap());
+            }
+        }
+    }
+}
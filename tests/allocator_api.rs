@@ -0,0 +1,81 @@
+use std::alloc::Layout;
+
+use allocator_api2::alloc::Allocator as _;
+use allocator_api2::boxed::Box;
+use allocator_api2::vec::Vec;
+use alloc_expr::{AVLTree, Allocator, LargeAllocator, Locked, RBTree};
+
+#[test]
+fn collections_use_their_own_allocator() {
+    let allocator: Allocator<AVLTree> = Allocator::new();
+
+    let mut small = Vec::new_in(&allocator);
+    let mut large = Vec::new_in(&allocator);
+    for value in 0..100_000u32 {
+        small.push(value as u8);
+        large.push(value);
+        if small.len() > 100 {
+            small.clear();
+            small.shrink_to_fit();
+        }
+    }
+    assert!(large.iter().enumerate().all(|(index, &value)| index as u32 == value));
+
+    let boxed = Box::new_in([7u64; 1000], &allocator);
+    assert_eq!(boxed.iter().sum::<u64>(), 7000);
+    assert!(allocator.stats().total().allocated_bytes > 0);
+
+    drop((small, large, boxed));
+    let stats = allocator.stats();
+    assert_eq!(stats.large.allocated_bytes, 0);
+    assert!(stats.classes.iter().all(|class| class.allocated == 0));
+}
+
+#[test]
+fn allocate_reports_the_real_capacity() {
+    let allocator: Allocator<AVLTree> = Allocator::new();
+
+    // rounded up to the 32 byte class
+    let block = allocator.allocate(Layout::from_size_align(20, 4).unwrap()).unwrap();
+    assert_eq!(block.len(), 32);
+    unsafe { allocator.deallocate(block.cast(), Layout::from_size_align(block.len(), 4).unwrap()) };
+
+    // runs to the end of the chunk's last page
+    let block = allocator.allocate(Layout::from_size_align(10_000, 8).unwrap()).unwrap();
+    assert!(block.len() >= 10_000);
+    assert!((block.cast::<u8>().as_ptr() as usize + block.len()).is_multiple_of(4096));
+    unsafe { allocator.deallocate(block.cast(), Layout::from_size_align(10_000, 8).unwrap()) };
+
+    let zero_sized = allocator.allocate(Layout::from_size_align(0, 64).unwrap()).unwrap();
+    assert_eq!(zero_sized.len(), 0);
+    assert!((zero_sized.cast::<u8>().as_ptr() as usize).is_multiple_of(64));
+}
+
+#[test]
+fn locked_trees_serve_collections() {
+    let avl = Locked::new(AVLTree::new());
+    let rb = Locked::new(RBTree::new());
+
+    let mut a = Vec::new_in(&avl);
+    let mut b = Vec::new_in(&rb);
+    for value in 0..50_000u64 {
+        a.push(value);
+        b.push(value);
+    }
+    assert_eq!(a, b);
+
+    // changing the alignment has to move the data to a fresh block
+    let block = avl.allocate(Layout::from_size_align(5000, 8).unwrap()).unwrap();
+    unsafe {
+        block.cast::<u8>().as_ptr().write_bytes(3, 5000);
+        let old_layout = Layout::from_size_align(5000, 8).unwrap();
+        let new_layout = Layout::from_size_align(9000, 1 << 16).unwrap();
+        let grown = avl.grow(block.cast(), old_layout, new_layout).unwrap();
+        assert!((grown.cast::<u8>().as_ptr() as usize).is_multiple_of(1 << 16));
+        assert_eq!(*grown.cast::<u8>().as_ptr().add(4999), 3);
+        avl.deallocate(grown.cast(), new_layout);
+    }
+
+    drop((a, b));
+    assert_eq!(avl.with(|tree| tree.stats().allocated_bytes), 0);
+}
@@ -0,0 +1,198 @@
+use std::ffi::c_void;
+use std::hint::black_box;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc::{EINVAL, ENOMEM};
+
+const PAGE_SIZE: usize = 4096;
+
+// nothing from the crate is used by name, this links it in so the test binary exports its malloc
+// family, and the calls below resolve to those rather than glibc's
+extern crate alloc_expr;
+
+extern "C" {
+    fn malloc(size: usize) -> *mut c_void;
+    fn free(ptr: *mut c_void);
+    fn calloc(count: usize, size: usize) -> *mut c_void;
+    fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void;
+    fn posix_memalign(out: *mut *mut c_void, align: usize, size: usize) -> i32;
+    fn aligned_alloc(align: usize, size: usize) -> *mut c_void;
+    fn memalign(align: usize, size: usize) -> *mut c_void;
+    fn valloc(size: usize) -> *mut c_void;
+    fn pvalloc(size: usize) -> *mut c_void;
+    fn malloc_usable_size(ptr: *mut c_void) -> usize;
+}
+
+fn errno() -> i32 {
+    unsafe { *libc::__errno_location() }
+}
+
+fn is_aligned(ptr: *mut c_void, align: usize) -> bool {
+    (ptr as usize).is_multiple_of(align)
+}
+
+#[test]
+fn malloc_and_free() {
+    for size in [0, 1, 10, 100, 1000, 5000, 100_000, 3_000_000] {
+        unsafe {
+            let ptr = malloc(size);
+            assert!(!ptr.is_null());
+            assert!(is_aligned(ptr, 16), "{size} bytes at {ptr:p}");
+            // glibc would report a rounded up size, the prefix records the exact one
+            assert_eq!(malloc_usable_size(ptr), size);
+            ptr.cast::<u8>().write_bytes(0xa5, size);
+            free(ptr);
+        }
+    }
+    unsafe {
+        free(std::ptr::null_mut());
+        assert_eq!(malloc_usable_size(std::ptr::null_mut()), 0);
+    }
+}
+
+#[test]
+fn calloc_zeroes_and_checks_for_overflow() {
+    unsafe {
+        // dirty some memory first, so calloc is likely to be handed it back
+        let dirty = malloc(4000);
+        dirty.cast::<u8>().write_bytes(0xff, 4000);
+        free(dirty);
+
+        let ptr = calloc(100, 40).cast::<u8>();
+        assert!(std::slice::from_raw_parts(ptr, 4000).iter().all(|&byte| byte == 0));
+        free(ptr.cast());
+
+        // an allocation that's only checked for null can be optimized out and assumed to succeed
+        assert!(black_box(calloc(usize::MAX / 2, 3)).is_null());
+        assert_eq!(errno(), ENOMEM);
+    }
+}
+
+#[test]
+fn realloc_keeps_the_contents() {
+    unsafe {
+        let mut ptr = realloc(std::ptr::null_mut(), 10).cast::<u8>();
+        for index in 0..10 {
+            ptr.add(index).write(index as u8);
+        }
+        // through the size classes, into the large allocator and back down
+        for size in [20, 100, 2000, 300_000, 50, 10] {
+            ptr = realloc(ptr.cast(), size).cast();
+            assert!(!ptr.is_null());
+            assert_eq!(malloc_usable_size(ptr.cast()), size);
+            for index in 0..10 {
+                assert_eq!(*ptr.add(index), index as u8, "byte {index} changed at {size}");
+            }
+        }
+
+        // glibc's behaviour, the memory is freed and nothing comes back
+        assert!(realloc(ptr.cast(), 0).is_null());
+    }
+}
+
+#[test]
+fn aligned_allocations() {
+    for align in [8, 16, 64, 256, PAGE_SIZE, 4 * PAGE_SIZE, 1 << 21] {
+        for size in [1, 100, 10_000] {
+            unsafe {
+                let mut ptr = std::ptr::null_mut();
+                assert_eq!(posix_memalign(&mut ptr, align, size), 0);
+                assert!(is_aligned(ptr, align), "posix_memalign({align}, {size}) gave {ptr:p}");
+                assert_eq!(malloc_usable_size(ptr), size);
+                free(ptr);
+
+                let ptr = memalign(align, size);
+                assert!(is_aligned(ptr, align), "memalign({align}, {size}) gave {ptr:p}");
+                free(ptr);
+
+                let ptr = aligned_alloc(align, size);
+                assert!(is_aligned(ptr, align), "aligned_alloc({align}, {size}) gave {ptr:p}");
+                free(ptr);
+            }
+        }
+    }
+}
+
+#[test]
+fn bad_alignments_are_rejected() {
+    unsafe {
+        let mut ptr = std::ptr::null_mut();
+        // not a power of two, and a power of two smaller than a pointer
+        assert_eq!(posix_memalign(&mut ptr, 24, 100), EINVAL);
+        assert_eq!(posix_memalign(&mut ptr, 4, 100), EINVAL);
+        assert!(ptr.is_null());
+
+        assert!(black_box(memalign(24, 100)).is_null());
+        assert_eq!(errno(), EINVAL);
+    }
+}
+
+#[test]
+fn valloc_and_pvalloc_give_pages() {
+    unsafe {
+        let ptr = valloc(100);
+        assert!(is_aligned(ptr, PAGE_SIZE));
+        assert_eq!(malloc_usable_size(ptr), 100);
+        free(ptr);
+
+        for (size, rounded) in [(0, PAGE_SIZE), (100, PAGE_SIZE), (PAGE_SIZE + 1, 2 * PAGE_SIZE)] {
+            let ptr = pvalloc(size);
+            assert!(is_aligned(ptr, PAGE_SIZE));
+            assert_eq!(malloc_usable_size(ptr), rounded);
+            ptr.cast::<u8>().write_bytes(0xa5, rounded);
+            free(ptr);
+        }
+    }
+}
+
+/// Forks over and over while other threads keep every lock busy. A child that inherited a held
+/// lock would hang on its first allocation.
+#[test]
+fn forking_while_other_threads_allocate() {
+    let stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for thread in 0..4 {
+            let stop = &stop;
+            scope.spawn(move || {
+                while !stop.load(Relaxed) {
+                    unsafe {
+                        let small = malloc(24 + thread * 100);
+                        let large = malloc(100_000);
+                        free(large);
+                        free(small);
+                    }
+                }
+            });
+        }
+
+        for _ in 0..200 {
+            let child = unsafe { libc::fork() };
+            if child == 0 {
+                unsafe {
+                    // every class and the large allocator
+                    for size in [8, 24, 100, 300, 1000, 100_000] {
+                        free(malloc(size));
+                    }
+                    libc::_exit(0);
+                }
+            }
+            assert!(child > 0, "fork failed");
+
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let mut status = 0;
+            while unsafe { libc::waitpid(child, &mut status, libc::WNOHANG) } == 0 {
+                if Instant::now() > deadline {
+                    unsafe { libc::kill(child, libc::SIGKILL) };
+                    stop.store(true, Relaxed);
+                    panic!("the child hung allocating after the fork");
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+        }
+        stop.store(true, Relaxed);
+    });
+}
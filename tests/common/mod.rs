@@ -0,0 +1,35 @@
+//! Helpers shared by the integration tests that need to watch a process die.
+
+/// Runs `f` in a forked child, returning the signal that killed it, if any, and what it wrote to
+/// stderr.
+pub fn run_in_child(f: impl FnOnce()) -> (Option<i32>, String) {
+    unsafe {
+        let mut pipe = [0; 2];
+        assert_eq!(libc::pipe(pipe.as_mut_ptr()), 0);
+        match libc::fork() {
+            -1 => panic!("fork failed"),
+            0 => {
+                libc::dup2(pipe[1], libc::STDERR_FILENO);
+                f();
+                libc::_exit(0);
+            }
+            child => {
+                libc::close(pipe[1]);
+                let mut output = Vec::new();
+                let mut buffer = [0u8; 1024];
+                loop {
+                    match libc::read(pipe[0], buffer.as_mut_ptr().cast(), buffer.len()) {
+                        read if read > 0 => output.extend_from_slice(&buffer[..read as usize]),
+                        _ => break,
+                    }
+                }
+                libc::close(pipe[0]);
+
+                let mut status = 0;
+                assert_eq!(libc::waitpid(child, &mut status, 0), child);
+                let signal = libc::WIFSIGNALED(status).then(|| libc::WTERMSIG(status));
+                (signal, String::from_utf8_lossy(&output).into_owned())
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::alloc::{GlobalAlloc, Layout};
+
+use alloc_expr::common::PAGE_SIZE;
+use alloc_expr::{AVLTree, Allocator, Chunk, LargeAllocator, LinkedList, RBTree};
+
+/// Whether the page holding `address` is mapped, mincore fails with ENOMEM on an unmapped one.
+fn is_mapped(address: usize) -> bool {
+    let page = address - address % PAGE_SIZE;
+    let mut resident = 0u8;
+    unsafe { libc::mincore(page as *mut libc::c_void, PAGE_SIZE, &mut resident) == 0 }
+}
+
+/// Fills an allocator's lists, thread cache and large allocator, frees all but the first round,
+/// then drops it.
+fn check_drop<T: LargeAllocator + Default>() {
+    let allocator: Box<Allocator<T>> = Box::new(Allocator::new());
+    let layouts = [8, 24, 100, 500, 2000, 40_000, 300_000].map(|size| Layout::from_size_align(size, 8).unwrap());
+    let pointers: Vec<(usize, Layout)> = (0..200)
+        .flat_map(|_| layouts)
+        .map(|layout| (unsafe { allocator.alloc(layout) } as usize, layout))
+        .collect();
+    let (kept, freed) = pointers.split_at(layouts.len());
+    for &(ptr, layout) in freed {
+        unsafe { allocator.dealloc(ptr as *mut u8, layout) };
+    }
+    assert!(allocator.stats().total().mapped_bytes > 0);
+    assert!(kept.iter().all(|&(ptr, _)| is_mapped(ptr)));
+
+    drop(allocator);
+    // small objects go with the pages they were carved from whether they were freed or not, a
+    // large allocation that's still in use is left alone
+    let small = kept.iter().filter(|(_, layout)| layout.size() <= 1024);
+    for &(ptr, layout) in freed.iter().chain(small) {
+        assert!(!is_mapped(ptr), "{} byte allocation at {ptr:#x} is still mapped", layout.size());
+    }
+    assert!(kept.iter().filter(|(_, layout)| layout.size() > 1024).all(|&(ptr, _)| is_mapped(ptr)));
+}
+
+/// Refills a list of page sized objects often enough that its record of refills runs over more
+/// than one page, then drops it.
+fn check_list_drop() {
+    let mut list = LinkedList::new();
+    let objects: Vec<usize> = (0..3000).map(|_| unsafe { list.alloc(PAGE_SIZE) } as usize).collect();
+    assert_eq!(list.refills(), 750);
+
+    drop(list);
+    assert!(objects.iter().all(|&object| !is_mapped(object)));
+}
+
+// a single test, so no other test thread can map something where this one expects a hole
+#[test]
+fn dropping_an_allocator_unmaps_its_memory() {
+    check_drop::<AVLTree>();
+    check_drop::<RBTree<Chunk>>();
+    check_list_drop();
+}
@@ -0,0 +1,84 @@
+// the guard pages are only there in debug builds
+#![cfg(debug_assertions)]
+
+use std::alloc::Layout;
+
+use alloc_expr::{AVLTree, LargeAllocator};
+use common::run_in_child;
+
+mod common;
+
+const PAGE_SIZE: usize = 4096;
+
+#[test]
+fn data_ends_at_the_guard_page() {
+    let mut tree = AVLTree::new();
+    for align in [8, 64, PAGE_SIZE, 4 * PAGE_SIZE] {
+        for size in [align, 3 * align, 70_000usize.next_multiple_of(align)] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            unsafe {
+                let ptr = tree.alloc(layout);
+                assert_eq!(ptr as usize % align, 0, "{layout:?} is misaligned");
+                assert_eq!((ptr as usize + size) % PAGE_SIZE, 0, "{layout:?} doesn't end at a page");
+                assert_eq!(tree.usable_size(ptr, layout), size);
+
+                ptr.write_bytes(0xa5, size);
+                tree.dealloc(ptr);
+            }
+        }
+    }
+
+    // nothing is cached, a freed chunk and its guard pages are unmapped straight away
+    let stats = tree.stats();
+    assert_eq!(stats.mapped_bytes, 0);
+    assert_eq!(tree.cached_bytes(), 0);
+}
+
+#[test]
+fn realloc_keeps_the_data_against_the_guard_page() {
+    let mut tree = AVLTree::new();
+    let mut layout = Layout::from_size_align(10_000, 8).unwrap();
+    unsafe {
+        let mut ptr = tree.alloc(layout);
+        let mut written = layout.size();
+        for offset in 0..written {
+            ptr.add(offset).write(offset as u8);
+        }
+
+        for new_size in [50_000, 20_000, 1_000] {
+            ptr = tree.realloc(ptr, layout, new_size);
+            assert_eq!((ptr as usize + new_size) % PAGE_SIZE, 0, "{new_size} doesn't end at a page");
+            written = written.min(new_size);
+            for offset in 0..written {
+                assert_eq!(*ptr.add(offset), offset as u8, "byte {offset} changed");
+            }
+            layout = Layout::from_size_align(new_size, 8).unwrap();
+        }
+        tree.dealloc(ptr);
+    }
+    assert_eq!(tree.stats().mapped_bytes, 0);
+}
+
+#[test]
+fn overrun_faults() {
+    let mut tree = AVLTree::new();
+    let layout = Layout::from_size_align(20_000, 8).unwrap();
+    let ptr = unsafe { tree.alloc(layout) };
+
+    let (signal, _) = run_in_child(|| unsafe { ptr.add(layout.size()).write_volatile(1) });
+    assert_eq!(signal, Some(libc::SIGSEGV));
+    unsafe { tree.dealloc(ptr) };
+}
+
+#[test]
+fn underrun_faults() {
+    let mut tree = AVLTree::new();
+    // small enough that the header shares the data's page
+    let layout = Layout::from_size_align(1000, 8).unwrap();
+    let ptr = unsafe { tree.alloc(layout) };
+
+    let before = (ptr as usize & !(PAGE_SIZE - 1)) - 1;
+    let (signal, _) = run_in_child(|| unsafe { (before as *mut u8).write_volatile(1) });
+    assert_eq!(signal, Some(libc::SIGSEGV));
+    unsafe { tree.dealloc(ptr) };
+}
@@ -0,0 +1,108 @@
+// the poisoning is only there in debug builds
+#![cfg(debug_assertions)]
+
+use std::alloc::{GlobalAlloc, Layout};
+
+use alloc_expr::{AVLTree, Allocator};
+use common::run_in_child;
+
+mod common;
+
+const PATTERN: u8 = 0x5a;
+
+#[test]
+fn freed_objects_are_poisoned() {
+    let allocator: Allocator<AVLTree> = Allocator::new();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let object = allocator.alloc(layout);
+        object.write_bytes(0, layout.size());
+        allocator.dealloc(object, layout);
+        // the first word links the object into its list
+        for offset in size_of::<usize>()..layout.size() {
+            assert_eq!(*object.add(offset), PATTERN, "byte {offset} isn't poisoned");
+        }
+        // handing it out again finds the pattern intact
+        assert_eq!(allocator.alloc(layout), object);
+    }
+}
+
+#[test]
+fn write_after_free_of_an_object_aborts() {
+    let allocator: Allocator<AVLTree> = Allocator::new();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let object = unsafe { allocator.alloc(layout) };
+    unsafe { allocator.dealloc(object, layout) };
+
+    let (signal, report) = run_in_child(|| unsafe {
+        object.add(20).write(1);
+        allocator.alloc(layout);
+    });
+    assert_eq!(signal, Some(libc::SIGABRT));
+    assert!(report.contains(&format!("chunk {object:p}, first modified byte at offset 0x14")), "{report}");
+    assert!(report.contains("  0x0010: 5a 5a 5a 5a 01 5a"), "{report}");
+}
+
+// with guard pages a chunk is unmapped as soon as it's freed, so there's nothing to poison
+#[cfg(not(feature = "guard-pages"))]
+mod chunks {
+    use alloc_expr::LargeAllocator;
+
+    use super::*;
+
+    const PAGE_SIZE: usize = 4096;
+
+    #[test]
+    fn freed_chunks_are_poisoned() {
+        let mut tree = AVLTree::new();
+        let layout = Layout::from_size_align(20_000, 8).unwrap();
+        unsafe {
+            let data = tree.alloc(layout);
+            data.write_bytes(0, layout.size());
+            tree.dealloc(data);
+            for offset in 0..layout.size() {
+                assert_eq!(*data.add(offset), PATTERN, "byte {offset} isn't poisoned");
+            }
+            assert_eq!(tree.alloc(layout), data);
+        }
+    }
+
+    #[test]
+    fn reuse_after_coalescing_passes() {
+        let mut tree = AVLTree::new();
+        let layout = Layout::from_size_align(3 * PAGE_SIZE, 8).unwrap();
+        let big = Layout::from_size_align(20 * PAGE_SIZE, 8).unwrap();
+        unsafe {
+            // split one chunk into several, then free them so they merge back together
+            let whole = tree.alloc(big);
+            tree.dealloc(whole);
+            let pieces: Vec<_> = (0..5).map(|_| tree.alloc(layout)).collect();
+            for &piece in &pieces {
+                piece.write_bytes(0xff, layout.size());
+            }
+            for &piece in pieces.iter().rev().step_by(2).chain(pieces.iter().skip(1).step_by(2)) {
+                tree.dealloc(piece);
+            }
+            // the merged chunk is checked headers and all, the old ones are poisoned too
+            assert_eq!(tree.alloc(big), whole);
+        }
+    }
+
+    #[test]
+    fn write_after_free_of_a_chunk_aborts() {
+        let mut tree = AVLTree::new();
+        let layout = Layout::from_size_align(20_000, 8).unwrap();
+        let data = unsafe { tree.alloc(layout) };
+        unsafe { tree.dealloc(data) };
+
+        let (signal, report) = run_in_child(|| unsafe {
+            data.add(100).write(1);
+            tree.alloc(layout);
+        });
+        // the data starts in the chunk's first page, right after its header
+        let chunk = data as usize & !(PAGE_SIZE - 1);
+        let offset = data as usize - chunk + 100;
+        assert_eq!(signal, Some(libc::SIGABRT));
+        assert!(report.contains(&format!("chunk {chunk:#x}, first modified byte at offset {offset:#x}")), "{report}");
+    }
+}
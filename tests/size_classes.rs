@@ -0,0 +1,37 @@
+use std::alloc::{GlobalAlloc, Layout};
+
+use alloc_expr::{AVLTree, Allocator, SizeClasses};
+
+const CLASSES: SizeClasses<12> = SizeClasses::geometric(4);
+
+#[test]
+fn allocator_with_custom_classes() {
+    let allocator: Allocator<AVLTree, 12> = Allocator::with_size_classes(CLASSES);
+    let mut live = Vec::new();
+    for class in 0..12 {
+        let size = CLASSES.size(class);
+        let layout = Layout::from_size_align(size, 16).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { ptr.write_bytes(class as u8, size) };
+        live.push((ptr, layout));
+    }
+
+    // a 48 byte object aligned to 32 comes out of the 64 byte class
+    let aligned = Layout::from_size_align(48, 32).unwrap();
+    let ptr = unsafe { allocator.alloc(aligned) };
+    assert_eq!(ptr as usize % 32, 0);
+    live.push((ptr, aligned));
+
+    let stats = allocator.stats();
+    for (class, class_stats) in stats.classes.iter().enumerate() {
+        assert_eq!(class_stats.size, CLASSES.size(class));
+        assert_eq!(class_stats.allocated, if class == 3 { 2 } else { 1 }, "class {class}");
+    }
+    assert_eq!(stats.large.allocated_bytes, 0);
+
+    for (ptr, layout) in live {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+    assert!(allocator.stats().classes.iter().all(|class| class.allocated == 0));
+}
@@ -0,0 +1,39 @@
+use std::alloc::{GlobalAlloc, Layout};
+
+use alloc_expr::{AVLTree, Allocator, LargeAllocator};
+
+#[test]
+fn stats_dont_wait_for_the_large_allocator() {
+    let allocator: Allocator<AVLTree> = Allocator::new();
+    let layout = Layout::from_size_align(100_000, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+
+    // the large allocator's lock is held for the whole closure
+    let (inside, tree) = allocator.inspect_large(|tree| (allocator.stats().large, tree.stats())).unwrap();
+    assert_eq!(inside, tree);
+    assert_eq!(inside.mmap_calls, 1);
+    assert!(inside.allocated_bytes >= layout.size());
+
+    unsafe { allocator.dealloc(ptr, layout) };
+    allocator.trim();
+    let large = allocator.stats().large;
+    assert_eq!((large.mapped_bytes, large.munmap_calls), (0, 1));
+}
+
+#[test]
+fn small_stats_count_every_mapping() {
+    let allocator: Box<Allocator<AVLTree>> = Box::new(Allocator::new());
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    // sixteen objects to a refill, so this takes two whether or not a thread cache asks for a
+    // whole batch
+    let objects: Vec<_> = (0..20).map(|_| unsafe { allocator.alloc(layout) }).collect();
+
+    // each refill is four pages, and the list maps one more page to record where they are
+    let small = allocator.stats().small;
+    assert_eq!((small.mapped_bytes, small.mmap_calls), (9 * 4096, 3));
+    assert_eq!(small.allocated_bytes + small.free_bytes, 2 * 4 * 4096);
+
+    for object in objects {
+        unsafe { allocator.dealloc(object, layout) };
+    }
+}
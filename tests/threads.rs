@@ -0,0 +1,60 @@
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::{Barrier, Mutex};
+use std::thread;
+
+use alloc_expr::{AVLTree, Allocator};
+
+/// More threads than there are thread caches, so some have to fall back to the shared lists.
+const THREADS: usize = 160;
+
+const SIZES: [usize; 7] = [8, 24, 48, 100, 256, 700, 1024];
+
+/// Allocates a batch of objects, hands half of them to whatever thread comes next and frees
+/// whatever the previous thread left behind.
+unsafe fn churn(allocator: &Allocator<AVLTree>, handoff: &Mutex<Vec<(usize, Layout)>>, seed: usize) {
+    let mut mine = Vec::new();
+    for round in 0..64 {
+        let size = SIZES[(seed + round) % SIZES.len()];
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = allocator.alloc(layout);
+        assert!(!ptr.is_null());
+        ptr.write_bytes(seed as u8, size);
+        mine.push((ptr as usize, layout));
+    }
+
+    let theirs = std::mem::replace(&mut *handoff.lock().unwrap(), mine.split_off(32));
+    for (ptr, layout) in mine.into_iter().chain(theirs) {
+        allocator.dealloc(ptr as *mut u8, layout);
+    }
+}
+
+#[test]
+fn more_threads_than_caches() {
+    let allocator: Allocator<AVLTree> = Allocator::new();
+    let handoff = Mutex::new(Vec::new());
+
+    // every thread stays alive until all of them have allocated, and the rounds after the first
+    // reuse the cache indices released by the threads that exited
+    for round in 0..3 {
+        let barrier = Barrier::new(THREADS);
+        thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let (allocator, handoff, barrier) = (&allocator, &handoff, &barrier);
+                scope.spawn(move || unsafe {
+                    barrier.wait();
+                    churn(allocator, handoff, round * THREADS + thread);
+                    barrier.wait();
+                });
+            }
+        });
+    }
+    for (ptr, layout) in handoff.into_inner().unwrap() {
+        unsafe { allocator.dealloc(ptr as *mut u8, layout) };
+    }
+
+    let stats = allocator.stats();
+    for class in stats.classes {
+        assert_eq!(class.allocated, 0, "{} byte objects are still allocated", class.size);
+    }
+    assert!(stats.small.free_bytes > 0);
+}